@@ -0,0 +1,77 @@
+use core::arch::x86_64::__cpuid;
+use conquer_once::spin::OnceCell;
+use spinning_top::Spinlock;
+use x2apic::lapic::{xapic_base, IpiAllShorthand, LocalApic, LocalApicBuilder};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::arch::x86_64::cpu;
+use crate::arch::x86_64::interrupts::InterruptIndex;
+use crate::arch::x86_64::memory::cpu_online;
+use crate::memory::{CachePolicy, KernelMemory, MmioRegion, PhysicalAddress};
+
+/// The local APIC's register page, when it is accessed through memory (xAPIC mode) rather than MSRs (x2APIC mode).
+type XApicRegisters = [u8; 4096];
+
+/// The local APIC. Every CPU sees its own local APIC through the same MSRs or registers, so one instance serves all.
+static LOCAL_APIC: OnceCell<Spinlock<LocalApic>> = OnceCell::uninit();
+
+/// Keeps the xAPIC registers mapped.
+static XAPIC_REGISTERS: OnceCell<MmioRegion<XApicRegisters>> = OnceCell::uninit();
+
+/// Enables the local APIC of the current CPU, and marks the CPU as online.
+pub fn init(kmm: &KernelMemory) {
+    let mut builder = LocalApicBuilder::new();
+    builder
+        .timer_vector(InterruptIndex::Timer.as_usize())
+        .error_vector(InterruptIndex::Error.as_usize())
+        .spurious_vector(InterruptIndex::Spurious.as_usize());
+
+    if !has_x2apic() {
+        let registers = unsafe {
+            // SAFETY: IA32_APIC_BASE points at the local APIC's registers, which nothing else maps.
+            MmioRegion::map(&mut kmm.vmm(), PhysicalAddress::new(xapic_base() as usize), CachePolicy::Uncached)
+        };
+        let registers = XAPIC_REGISTERS.get_or_init(|| registers.expect("Failed to map the local APIC"));
+        builder.set_xapic_base(registers.virtual_address().value() as u64);
+    }
+
+    let mut apic = builder.build().expect("Failed to configure the local APIC");
+    unsafe {
+        // SAFETY: Every vector the local APIC can raise has a handler in the IDT.
+        apic.enable();
+
+        // We don't have anything for the timer to drive yet.
+        apic.disable_timer();
+    }
+    LOCAL_APIC.init_once(|| Spinlock::new(apic));
+    cpu_online(cpu::current());
+}
+
+/// Sends an interrupt to every CPU except the current one.
+///
+/// Does nothing if the local APIC hasn't been enabled, since no other CPU can have been started without it.
+pub fn send_ipi_to_others(vector: InterruptIndex) {
+    if let Some(apic) = LOCAL_APIC.get() {
+        // Interrupt handlers take the lock to signal the end of the interrupt.
+        without_interrupts(|| unsafe {
+            // SAFETY: The vector has a handler in the IDT.
+            apic.lock().send_ipi_all(vector.as_u8(), IpiAllShorthand::AllExcludingSelf);
+        });
+    }
+}
+
+/// Signals the end of an interrupt raised by the local APIC.
+pub fn end_of_interrupt() {
+    if let Some(apic) = LOCAL_APIC.get() {
+        unsafe {
+            // SAFETY: This is only called at the end of interrupt handlers, which run with interrupts disabled.
+            apic.lock().end_of_interrupt();
+        }
+    }
+}
+
+fn has_x2apic() -> bool {
+    unsafe {
+        // SAFETY: CPUID is always available in long mode. Bit 21 of ECX is the "x2APIC" feature flag.
+        __cpuid(1).ecx & (1 << 21) != 0
+    }
+}
@@ -0,0 +1,70 @@
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::memory::{Page, VirtualAddress, STACK_RANGES};
+
+/// The bounds of the boot stack. The bootloader placed it, so it isn't necessarily in [`STACK_RANGES`].
+static BOOT_STACK_START: AtomicUsize = AtomicUsize::new(0);
+static BOOT_STACK_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Records where the boot stack is, so that it can be walked. Until this is called, only stacks allocated from
+/// [`STACK_RANGES`] can be.
+pub(super) fn set_boot_stack(stack: Range<VirtualAddress>) {
+    BOOT_STACK_START.store(stack.start.value(), Ordering::Relaxed);
+    BOOT_STACK_END.store(stack.end.value(), Ordering::Relaxed);
+}
+
+/// Collects the return addresses of the functions that called the current one, innermost first, skipping the first
+/// `skip` of them. Returns how many addresses were written to `out`.
+///
+/// This follows the chain of saved frame pointers, which the kernel is always built to keep. Walking stops as soon as
+/// a frame pointer leaves the stack the walk started on, or doesn't point above the previous one, so the result may be
+/// cut short, but nothing outside the stack is ever read.
+#[inline(never)]
+pub fn return_addresses(skip: usize, out: &mut [usize]) -> usize {
+    let mut frame: usize;
+    unsafe {
+        // SAFETY: Reading RBP has no side effects.
+        asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags));
+    }
+    let Some(stack) = stack_containing(frame) else {
+        return 0;
+    };
+
+    // This function's own return address comes first, so it is always skipped.
+    let mut skip = skip + 1;
+    let mut count = 0;
+    while count < out.len() && frame % 8 == 0 && stack.start <= frame && frame + 16 <= stack.end {
+        let (next, return_address) = unsafe {
+            // SAFETY: The frame is in a mapped kernel stack, where each frame starts with the caller's frame pointer,
+            // followed by the return address.
+            (*(frame as *const usize), *((frame + 8) as *const usize))
+        };
+        if return_address == 0 {
+            break;
+        }
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            out[count] = return_address;
+            count += 1;
+        }
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    count
+}
+
+/// Gets the bounds of the mapped stack that `address` is in, if it is in one we know about.
+fn stack_containing(address: usize) -> Option<Range<usize>> {
+    let boot_stack = BOOT_STACK_START.load(Ordering::Relaxed)..BOOT_STACK_END.load(Ordering::Relaxed);
+    if boot_stack.contains(&address) {
+        return Some(boot_stack);
+    }
+
+    // The code being traced may be allocating a stack itself, so don't wait for the lock.
+    let pages = STACK_RANGES.try_lock()?.range_containing(Page::containing(VirtualAddress::new(address)))?;
+    Some(pages.start.start_address().value()..pages.end.start_address().value())
+}
@@ -0,0 +1,57 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+/// The most CPUs the kernel can run on, since sets of CPUs are kept as bit masks in a `u64`.
+pub const MAX_CPUS: usize = 64;
+
+/// The data each CPU keeps about itself, which it finds through its GS base.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct PerCpu {
+    /// The CPU's index. This must stay the first field, since [`current`] reads it at offset 0.
+    index: u32,
+}
+
+static PER_CPU: [PerCpu; MAX_CPUS] = {
+    let mut cpus = [PerCpu { index: 0 }; MAX_CPUS];
+    let mut index = 0;
+    while index < MAX_CPUS {
+        cpus[index].index = index as u32;
+        index += 1;
+    }
+    cpus
+};
+
+/// The index the next CPU to call [`init`] gets.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the boot CPU has called [`init`]. Until then, it is the only CPU running.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Gives the current CPU the next free index, and points its GS base at its per-CPU data.
+///
+/// Every CPU must call this before anything else that asks for [`current`].
+pub fn init() {
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_CPUS, "only {} CPUs are supported", MAX_CPUS);
+    GsBase::write(VirtAddr::from_ptr(&PER_CPU[index]));
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// Gets the index of the CPU we're running on.
+///
+/// Indices are handed out densely, in the order the CPUs started, so the boot CPU is always 0. Reading it is a single
+/// load, so unlike the APIC ID, it is cheap enough for hot paths like TLB flushes and address space switches.
+pub fn current() -> u32 {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return 0;
+    }
+    let index: u32;
+    unsafe {
+        // SAFETY: `init` pointed the GS base at this CPU's `PerCpu`, whose first field is the index.
+        asm!("mov {:e}, gs:[0]", out(reg) index, options(nostack, readonly, preserves_flags));
+    }
+    index
+}
@@ -1,20 +1,36 @@
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
 use acpi::AcpiTables;
-use bootloader_api::BootloaderConfig;
+use bootloader_api::{BootInfo, BootloaderConfig};
 use bootloader_api::config::Mapping;
 use bootloader_api::info::Optional;
 use log::LevelFilter;
-use crate::arch::x86_64::{early, gdt, interrupts};
-use crate::arch::x86_64::memory::VirtualMemoryManager;
+use x86_64::instructions::random::RdRand;
+use crate::arch::x86_64::{apic, backtrace, cpu, early, gdt, interrupts, user_access};
+use crate::arch::x86_64::memory::{PagingMode, VirtualMemoryManager};
 use crate::Kernel;
-use crate::memory::{KernelMemory, VirtualMemoryManagerProtocol};
+use core::ops::Range;
+use crate::memory::{KernelLayout, KernelMemory, LayoutRandomizer, PageSize, VirtualAddress, VirtualMemoryManagerProtocol};
+
+/// The part of [`VirtualMemoryManagerProtocol::KERNEL_BOOT_SPACE`] the bootloader may place its own mappings in.
+/// The rest of the boot space is handed out by [`crate::memory::BOOT_RANGES`].
+const BOOTLOADER_DYNAMIC_SPACE: Range<VirtualAddress> = VirtualMemoryManager::KERNEL_BOOT_SPACE.start
+    ..VirtualAddress::new(VirtualMemoryManager::KERNEL_BOOT_SPACE.start.value() + 0x0800_0000_0000);
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
 
-    config.mappings.kernel_stack = Mapping::FixedAddress(VirtualMemoryManager::KERNEL_STACK_SPACE.start.value() as u64);
+    // With KASLR, the bootloader places the boot stack (along with its other dynamic mappings) at a random address in
+    // its dynamic range, and we move everything else ourselves.
+    config.mappings.aslr = cfg!(feature = "kaslr");
+    config.mappings.kernel_stack = if cfg!(feature = "kaslr") {
+        Mapping::Dynamic
+    } else {
+        Mapping::FixedAddress(VirtualMemoryManager::KERNEL_STACK_SPACE.start.value() as u64)
+    };
     config.mappings.physical_memory = Some(Mapping::FixedAddress(VirtualMemoryManager::KERNEL_PHYSICAL_SPACE.start.value() as u64));
-    config.mappings.dynamic_range_start = Some(VirtualMemoryManager::KERNEL_BOOT_SPACE.start.value() as u64);
-    config.mappings.dynamic_range_end = Some(VirtualMemoryManager::KERNEL_BOOT_SPACE.end.value() as u64);
+    config.mappings.dynamic_range_start = Some(BOOTLOADER_DYNAMIC_SPACE.start.value() as u64);
+    config.mappings.dynamic_range_end = Some(BOOTLOADER_DYNAMIC_SPACE.end.value() as u64);
 
     config
 };
@@ -38,13 +54,110 @@ fn entry(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         }
     );
 
+    // TLB flushes need to know which CPU they're on, and the VMM flushes as it sets up.
+    cpu::init();
+    backtrace::set_boot_stack(boot_stack());
+
+    let layout = choose_layout(boot_info);
+    let kmm = KernelMemory::new(VirtualMemoryManager::new(boot_info, &layout), &layout);
+    log::info!(
+        "Kernel layout ({}): heap at {:#x}, stacks from {:#x}, boot mappings from {:#x}, physical memory at {:#x}",
+        if cfg!(feature = "kaslr") { "randomized" } else { "fixed" },
+        layout.heap.value(),
+        layout.stacks.value(),
+        layout.boot.value(),
+        layout.physical_memory.value(),
+    );
+    log::info!(
+        "Paging: {:?}, with {}-bit virtual addresses (5-level paging {}supported)",
+        PagingMode::current(),
+        PagingMode::current().virtual_address_bits(),
+        if PagingMode::is_five_level_supported() { "" } else { "not " },
+    );
+
     // Configure interrupt and segmentation tables
-    gdt::init();
+    // The IST stacks are allocated from kernel memory, so this has to wait until memory is initialized.
+    gdt::init(&kmm);
     interrupts::init();
+    apic::init(&kmm);
+    user_access::init();
 
-    let kmm = KernelMemory::new(VirtualMemoryManager::new(boot_info));
+    end_early_boot(&kmm, boot_info);
 
     Kernel::new(
         kmm,
     ).run()
 }
+
+/// Ends the early boot phase, returning the memory used by the bootloader and the ACPI tables to the frame allocator.
+///
+/// Anything that needs the ACPI tables has to have read them before this.
+fn end_early_boot(kmm: &KernelMemory, boot_info: &BootInfo) {
+    let reclaimed = unsafe {
+        // SAFETY: Nothing past this point reads firmware or bootloader memory that it hasn't mapped itself.
+        kmm.vmm().reclaim_boot_memory(boot_info)
+    };
+    log::info!(
+        "Early boot finished: reclaimed {} frames ({} KiB) of bootloader and ACPI memory",
+        reclaimed,
+        reclaimed * VirtualMemoryManager::PAGE_SIZE / 1024);
+}
+
+/// Gets the bounds of the boot stack.
+///
+/// With KASLR, the bootloader chooses where it goes, but it always starts us at the top of it, which is page-aligned,
+/// and we're only a few frames down from there.
+fn boot_stack() -> Range<VirtualAddress> {
+    let stack_pointer: usize;
+    unsafe {
+        // SAFETY: Reading RSP has no side effects.
+        asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack, preserves_flags));
+    }
+    let top = VirtualAddress::new(stack_pointer).align_up(VirtualMemoryManager::PAGE_SIZE);
+    top - BOOTLOADER_CONFIG.kernel_stack_size as usize..top
+}
+
+/// Decides where the kernel's heap, stacks, boot-time mappings and physical memory map go.
+///
+/// Without KASLR, each starts just past whatever the bootloader has already put in its window. With it, each starts
+/// at a random offset in the lower half of its window (so there is still plenty of room above it).
+fn choose_layout(boot_info: &BootInfo) -> KernelLayout {
+    if !cfg!(feature = "kaslr") {
+        // The bootloader puts a guard page at the fixed address we gave it, and the stack directly above that.
+        let boot_stack_end = VirtualMemoryManager::KERNEL_STACK_SPACE.start
+            + VirtualMemoryManager::PAGE_SIZE
+            + BOOTLOADER_CONFIG.kernel_stack_size as usize;
+        return KernelLayout {
+            heap: VirtualMemoryManager::KERNEL_HEAP_SPACE.start,
+            stacks: boot_stack_end.align_up(VirtualMemoryManager::PAGE_SIZE),
+            boot: BOOTLOADER_DYNAMIC_SPACE.end,
+            physical_memory: VirtualMemoryManager::KERNEL_PHYSICAL_SPACE.start,
+        };
+    }
+
+    let half = |window: &Range<VirtualAddress>| (window.end.value() - window.start.value()) / 2;
+    let boot_space = BOOTLOADER_DYNAMIC_SPACE.end..VirtualMemoryManager::KERNEL_BOOT_SPACE.end;
+
+    // The bootloader's physical memory map is still in use while the new one is built, so they can't overlap.
+    let physical_size = VirtualMemoryManager::physical_memory_size(boot_info);
+    let physical_space = (VirtualMemoryManager::KERNEL_PHYSICAL_SPACE.start + physical_size).align_up(PageSize::Size1GiB.bytes())
+        ..VirtualMemoryManager::KERNEL_PHYSICAL_SPACE.end;
+
+    let mut randomizer = LayoutRandomizer::new(random_seed());
+    KernelLayout {
+        heap: randomizer.place(VirtualMemoryManager::KERNEL_HEAP_SPACE, half(&VirtualMemoryManager::KERNEL_HEAP_SPACE), PageSize::Size2MiB.bytes()),
+        stacks: randomizer.place(VirtualMemoryManager::KERNEL_STACK_SPACE, half(&VirtualMemoryManager::KERNEL_STACK_SPACE), VirtualMemoryManager::PAGE_SIZE),
+        boot: randomizer.place(boot_space.clone(), half(&boot_space), VirtualMemoryManager::PAGE_SIZE),
+        physical_memory: randomizer.place(physical_space, physical_size, PageSize::Size1GiB.bytes()),
+    }
+}
+
+/// Gets a seed for the layout randomizer, from RDRAND if the processor has it, or the time stamp counter otherwise.
+fn random_seed() -> u64 {
+    RdRand::new()
+        .and_then(|rdrand| rdrand.get_u64())
+        .unwrap_or_else(|| unsafe {
+            // SAFETY: The time stamp counter is always available in long mode.
+            _rdtsc()
+        })
+}
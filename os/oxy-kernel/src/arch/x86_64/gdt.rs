@@ -1,51 +1,51 @@
-use x86_64::VirtAddr;
+use conquer_once::spin::OnceCell;
 use x86_64::structures::tss::TaskStateSegment;
-use lazy_static::lazy_static;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{CS, Segment};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use crate::memory::{KernelMemory, KernelStack};
 
+// Page faults run on the stack they happened on, since resolving one may fault again (on a copy-on-write page, say),
+// and a nested fault on the same IST stack would overwrite the first one's frame.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub const PAGE_FAULT_IST_INDEX: u16 = 1;
-pub const GP_FAULT_IST_INDEX: u16 = 2;
-pub const STACK_FAULT_IST_INDEX: u16 = 3;
+pub const GP_FAULT_IST_INDEX: u16 = 1;
+pub const STACK_FAULT_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        fn create_stack() -> VirtAddr {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const IST_STACK_SIZE: usize = 4096 * 5;
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        }
+static TSS: OnceCell<TaskStateSegment> = OnceCell::uninit();
+static GDT: OnceCell<(GlobalDescriptorTable, Selectors)> = OnceCell::uninit();
+
+struct Selectors {
+    code_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+}
+
+pub fn init(kmm: &KernelMemory) {
+    let tss = TSS.get_or_init(|| {
+        let create_stack = || {
+            // IST stacks live for as long as the kernel does, so they are never freed.
+            let stack = KernelStack::allocate(kmm, IST_STACK_SIZE).expect("Failed to allocate an IST stack");
+            stack.top().into()
+        };
 
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = create_stack();
-        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = create_stack();
         tss.interrupt_stack_table[GP_FAULT_IST_INDEX as usize] = create_stack();
         tss.interrupt_stack_table[STACK_FAULT_IST_INDEX as usize] = create_stack();
         tss
-    };
+    });
 
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    let (gdt, selectors) = GDT.get_or_init(|| {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.append(Descriptor::tss_segment(tss));
         (gdt, Selectors { code_selector, tss_selector })
-    };
-}
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-}
+    });
 
-pub fn init() {
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        load_tss(selectors.tss_selector);
     }
 }
@@ -1,7 +1,10 @@
 use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use crate::arch::x86_64::memory::handle_tlb_shootdown;
+use crate::memory::{self, FaultAccess, VirtualAddress};
 
-use super::gdt;
+use super::{apic, gdt, user_access};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = create_idt();
@@ -13,16 +16,17 @@ const APIC_BASE_VECTOR: u8 = 0x20;
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = 0xfe,
+    TlbShootdown = 0xfd,
     Error = 0x31,
     Spurious = 0xff,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
 }
@@ -38,10 +42,18 @@ pub fn init() {
 fn create_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    // unsafe {
-    //     // SAFETY: We configure the stack in super::gdt::init(), and we don't reuse this IST index anywhere in this IDT.
-    //     idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-    // }
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt[InterruptIndex::TlbShootdown.as_u8()].set_handler_fn(tlb_shootdown_handler);
+    idt[InterruptIndex::Error.as_u8()].set_handler_fn(apic_error_handler);
+    idt[InterruptIndex::Spurious.as_u8()].set_handler_fn(spurious_interrupt_handler);
+    unsafe {
+        // SAFETY: We configure the stacks in super::gdt::init(), and we don't reuse these IST indices anywhere in
+        // this IDT. A kernel stack overflow faults on the guard page, and the page fault can't be delivered on the
+        // exhausted stack either, so it becomes a double fault, which has to run on a stack of its own.
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.general_protection_fault.set_handler_fn(gp_fault_handler).set_stack_index(gdt::GP_FAULT_IST_INDEX);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler).set_stack_index(gdt::STACK_FAULT_IST_INDEX);
+    }
     idt
 }
 
@@ -49,6 +61,20 @@ extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFra
     log::info!("Timer tick");
 }
 
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    handle_tlb_shootdown();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
+    log::error!("Local APIC error");
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts must not be acknowledged.
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     log::info!("BREAKPOINT: {:#?}", stack_frame);
 }
@@ -63,8 +89,34 @@ extern "x86-interrupt" fn gp_fault_handler(stack_frame: InterruptStackFrame, err
     //panic!("GENERAL PROTECTION FAULT {:?}: {:#?}", error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    panic!("PAGE FAULT {:?}: {:#?}", error_code, stack_frame);
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let address = VirtualAddress::new(Cr2::read_raw() as usize);
+
+    let result = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+        // Writes to present pages may just be the first write to a copy-on-write page, in either user or kernel mode.
+        memory::resolve_copy_on_write(address)
+    } else if !error_code.intersects(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE) {
+        let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            FaultAccess::Execute
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        memory::resolve_page_fault(address, access)
+    } else {
+        // Other faults on pages that are present are protection violations, which no amount of mapping will fix.
+        // There are no user tasks to kill yet, so faults from user mode are violations too.
+        Err(memory::Error::AccessViolation)
+    };
+
+    if let Err(e) = result {
+        // A fault while copying to or from user memory just ends the copy, which reports it to whoever asked for it.
+        if user_access::fixup_user_access_fault(&mut stack_frame) {
+            return;
+        }
+        panic!("PAGE FAULT at {:#X} ({}) {:?}: {:#?}", address.value(), e, error_code, stack_frame);
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
@@ -0,0 +1,274 @@
+use core::iter::Step;
+use x86_64::instructions::tlb::Pcid;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, PageTableFlags, PhysFrame, Translate};
+use x86_64::structures::paging::mapper::TranslateResult;
+use crate::arch::x86_64::memory::paging::{self, PagingMode};
+use crate::arch::x86_64::memory::tlb::{self, TlbFlush};
+use crate::arch::x86_64::memory::vmm::{self, BORROWED_FRAME, COPY_ON_WRITE, KERNEL_LEVEL_4_ENTRIES};
+use crate::memory::{AddressSpaceProtocol, Error, FlushPromise, Frame, Mapping, Page, PageFlags, VirtualAddress, VirtualMemoryManagerProtocol};
+use super::VirtualMemoryManager;
+
+/// An address space with its own level 4 page table.
+///
+/// The upper half of the table is copied from the kernel's table when the address space is created. Because the VMM
+/// populates every kernel level 4 entry at boot, those entries never change, so kernel mappings stay shared. With
+/// 5-level paging, the address space has its own level 5 table instead, which shares the kernel's whole level 4 table.
+pub struct AddressSpace {
+    /// The table CR3 points at while the address space is active. With 4-level paging, this is the level 4 table.
+    root_frame: PhysFrame,
+
+    /// The level 4 table that holds user space.
+    level_4_frame: PhysFrame,
+
+    /// The PCID the address space's TLB entries are tagged with, if PCIDs are enabled and there was one left.
+    pcid: Option<Pcid>,
+}
+
+impl AddressSpace {
+    /// Gets a mapper for this address space's page tables.
+    ///
+    /// # Safety
+    /// The caller must make sure no other mapper for this address space exists at the same time.
+    unsafe fn mapper(&self, vmm: &VirtualMemoryManager) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(vmm.page_table_at(self.level_4_frame), vmm.physical_memory_offset())
+    }
+}
+
+impl AddressSpaceProtocol for AddressSpace {
+    fn try_new(vmm: &mut VirtualMemoryManager) -> Result<Self, Error> {
+        let level_4_frame = vmm.allocate_page_table()?;
+        let table = unsafe {
+            // SAFETY: We just allocated the table, so nothing else refers to it.
+            vmm.page_table_at(level_4_frame)
+        };
+        if PagingMode::current() == PagingMode::FourLevel {
+            for index in KERNEL_LEVEL_4_ENTRIES {
+                table[index] = vmm.kernel_level_4_table()[index].clone();
+            }
+        }
+        let root_frame = match paging::new_root(vmm, level_4_frame) {
+            Ok(root_frame) => root_frame,
+            Err(e) => {
+                vmm.frame_allocator().deallocate(level_4_frame.into());
+                return Err(e);
+            },
+        };
+        Ok(AddressSpace { root_frame, level_4_frame, pcid: tlb::allocate_pcid() })
+    }
+
+    fn try_map(&mut self, vmm: &mut VirtualMemoryManager, page: Page, flags: PageFlags) -> Result<TlbFlush, Error> {
+        if !VirtualMemoryManager::USER_SPACE.contains(&page.start_address()) {
+            return Err(Error::OutsideUserSpace);
+        }
+
+        let flags = vmm.page_table_flags(flags | PageFlags::USER)?;
+        let frame = vmm.frame_allocator().allocate().ok_or(Error::FrameAllocationFailed)?;
+        unsafe {
+            // SAFETY: We just allocated the frame, and all of physical memory is mapped at the offset.
+            // User pages must not leak whatever the kernel last stored in the frame.
+            let frame_ptr = (vmm.physical_memory_offset() + frame.start_address().value() as u64).as_mut_ptr::<u8>();
+            frame_ptr.write_bytes(0, VirtualMemoryManager::PAGE_SIZE);
+        }
+
+        let result = unsafe {
+            // SAFETY: We just allocated the frame, and `&mut self` makes sure this is the only mapper.
+            self.mapper(vmm).map_to(page.into(), frame.into(), flags, vmm.frame_allocator())
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                Ok(TlbFlush::page(page).in_address_space(self.pcid))
+            },
+            Err(e) => {
+                vmm.frame_allocator().deallocate(frame);
+                Err(e.into())
+            },
+        }
+    }
+
+    fn try_unmap(&mut self, vmm: &mut VirtualMemoryManager, page: Page) -> Result<TlbFlush, Error> {
+        if !VirtualMemoryManager::USER_SPACE.contains(&page.start_address()) {
+            return Err(Error::OutsideUserSpace);
+        }
+
+        let mut mapper = unsafe {
+            // SAFETY: `&mut self` makes sure this is the only mapper.
+            self.mapper(vmm)
+        };
+        let flags = match mapper.translate(page.start_address().into()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => return Err(Error::PageNotMapped),
+        };
+        let (frame, flush) = mapper.unmap(page.into())?;
+        flush.ignore();
+        let flush = TlbFlush::page(page).in_address_space(self.pcid);
+        if flags.contains(BORROWED_FRAME) {
+            return Ok(flush);
+        }
+
+        // Another CPU may still reach the frame through its TLB, so it can only be freed once the page is invalidated.
+        flush.flush();
+        vmm.frame_allocator().deallocate(frame.into());
+
+        // The page was invalidated before its frame was freed, so the caller has nothing left to flush.
+        Ok(TlbFlush::none())
+    }
+
+    fn try_clone(&mut self, vmm: &mut VirtualMemoryManager) -> Result<Self, Error> {
+        let clone = Self::try_new(vmm)?;
+        let result = {
+            let mut clone_mapper = unsafe {
+                // SAFETY: Nothing else knows about the clone yet.
+                clone.mapper(vmm)
+            };
+            let table = unsafe {
+                // SAFETY: `&mut self` makes sure nothing else is changing our tables.
+                vmm.page_table_at(self.level_4_frame)
+            };
+            (0..KERNEL_LEVEL_4_ENTRIES.start)
+                .filter(|&index| !table[index].is_unused())
+                .try_for_each(|index| {
+                    let frame = PhysFrame::containing_address(table[index].addr());
+                    share_table(vmm, frame, 3, index << 39, &mut clone_mapper)
+                })
+        };
+
+        // Some of our pages may have been made read-only, even if sharing the rest failed.
+        tlb::mark_stale(self.pcid);
+        if self.is_active() {
+            tlb::flush_everything();
+        }
+
+        match result {
+            Ok(()) => Ok(clone),
+            Err(e) => {
+                clone.destroy(vmm)?;
+                Err(e)
+            },
+        }
+    }
+
+    fn try_resolve_copy_on_write(&mut self, vmm: &mut VirtualMemoryManager, page: Page) -> Result<TlbFlush, Error> {
+        let mut mapper = unsafe {
+            // SAFETY: `&mut self` makes sure this is the only mapper.
+            self.mapper(vmm)
+        };
+        Ok(vmm::resolve_copy_on_write(vmm, &mut mapper, page)?.in_address_space(self.pcid))
+    }
+
+    fn walk_mappings(&self, vmm: &VirtualMemoryManager, visit: &mut dyn FnMut(Mapping)) {
+        let table = unsafe {
+            // SAFETY: We only read the table, and `&self` keeps the address space from being destroyed meanwhile.
+            vmm.page_table_at(self.level_4_frame)
+        };
+        vmm::walk_mappings(vmm, table, 4, 0, visit);
+        if self.root_frame != self.level_4_frame {
+            // With 5-level paging, the kernel's mappings aren't in our level 4 table.
+            VirtualMemoryManagerProtocol::walk_mappings(vmm, visit);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.root_frame
+    }
+
+    unsafe fn activate(&self) {
+        tlb::switch_address_space(self.root_frame, self.pcid);
+    }
+
+    fn destroy(self, vmm: &mut VirtualMemoryManager) -> Result<(), Error> {
+        if self.is_active() {
+            return Err(Error::AddressSpaceActive);
+        }
+
+        let table = unsafe {
+            // SAFETY: We own the address space, and it isn't active, so nothing else is using its tables.
+            vmm.page_table_at(self.level_4_frame)
+        };
+        for entry in table.iter().take(KERNEL_LEVEL_4_ENTRIES.start) {
+            if !entry.is_unused() {
+                free_table(vmm, PhysFrame::containing_address(entry.addr()), 3);
+            }
+        }
+        // The kernel half is shared, so only the level 4 table itself is ours to free.
+        vmm.frame_allocator().deallocate(self.level_4_frame.into());
+        if self.root_frame != self.level_4_frame {
+            vmm.frame_allocator().deallocate(self.root_frame.into());
+        }
+        if let Some(pcid) = self.pcid {
+            tlb::free_pcid(pcid);
+        }
+        Ok(())
+    }
+}
+
+/// Shares every page mapped by a user page table of the given level with `clone_mapper`, making writable pages
+/// copy-on-write. `base` is the first address covered by the table.
+fn share_table(vmm: &mut VirtualMemoryManager, frame: PhysFrame, level: u32, base: usize, clone_mapper: &mut OffsetPageTable) -> Result<(), Error> {
+    let table = unsafe {
+        // SAFETY: The table belongs to an address space that is borrowed mutably by `try_clone`.
+        vmm.page_table_at(frame)
+    };
+    for (index, entry) in table.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+        let address = base + (index << (12 + 9 * (level - 1)));
+        if level > 1 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(Error::Other("huge user pages can't be shared copy-on-write"));
+        }
+        if level > 1 {
+            share_table(vmm, PhysFrame::containing_address(entry.addr()), level - 1, address, clone_mapper)?;
+            continue;
+        }
+
+        let mut flags = entry.flags();
+        let frame: PhysFrame = PhysFrame::containing_address(entry.addr());
+        if !flags.contains(BORROWED_FRAME) {
+            if !vmm.frame_allocator().add_reference(frame.into()) {
+                return Err(Error::Other("frame has too many references"));
+            }
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+        }
+
+        let page = Page::containing(VirtualAddress::new(address));
+        let result = unsafe {
+            // SAFETY: The frame is shared read-only (or was borrowed to begin with), so neither address space can
+            // change it behind the other's back.
+            clone_mapper.map_to(page.into(), frame, flags, vmm.frame_allocator())
+        };
+        match result {
+            // The clone isn't active, so there is nothing to flush.
+            Ok(flush) => flush.ignore(),
+            Err(e) => {
+                if !flags.contains(BORROWED_FRAME) {
+                    vmm.frame_allocator().deallocate(frame.into());
+                }
+                return Err(e.into());
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Frees a user page table of the given level, along with every table and frame it maps.
+fn free_table(vmm: &mut VirtualMemoryManager, frame: PhysFrame, level: u32) {
+    let table = unsafe {
+        // SAFETY: The table belongs to an address space that is being destroyed.
+        vmm.page_table_at(frame)
+    };
+    for entry in table.iter().filter(|e| !e.is_unused()) {
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if !entry.flags().contains(BORROWED_FRAME) {
+                let first_frame = Frame::containing(entry.addr().into());
+                for frame in first_frame..Step::forward(first_frame, 512usize.pow(level - 1)) {
+                    vmm.frame_allocator().deallocate(frame);
+                }
+            }
+        } else {
+            free_table(vmm, PhysFrame::containing_address(entry.addr()), level - 1);
+        }
+    }
+    vmm.frame_allocator().deallocate(frame.into());
+}
@@ -0,0 +1,63 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::VirtAddr;
+use crate::arch::prelude::*;
+use crate::memory::{Frame, PhysicalAddress, PhysicalFrameAllocator};
+
+/// The UEFI memory type of ACPI tables that can be reclaimed once they have been read (`EfiACPIReclaimMemory`).
+const UEFI_ACPI_RECLAIMABLE: u32 = 9;
+
+/// The E820 memory type of ACPI tables that can be reclaimed once they have been read.
+const BIOS_ACPI_RECLAIMABLE: u32 = 3;
+
+/// Creates a frame allocator seeded with the usable frames from the bootloader's memory map.
+///
+/// The allocator tracks every frame up to the end of the highest usable or reclaimable region, so that the frames
+/// reclaimed after boot (see [`VirtualMemoryManager::reclaim_boot_memory`]) can be handed out too, even if they lie
+/// above all of the usable memory. The allocator's bitmap is placed at the start of the first usable region that is
+/// large enough to hold it, and the frames it occupies are reserved so they are never handed out.
+///
+/// # Safety
+/// The caller must guarantee that the passed memory map is valid (all frames marked as `Usable` are really unused),
+/// that the complete physical memory is mapped at `physical_memory_offset`, and that this is only called once.
+pub unsafe fn init_frame_allocator(memory_map: &MemoryRegions, physical_memory_offset: VirtAddr) -> PhysicalFrameAllocator {
+    let usable_regions = || memory_map.iter().filter(|r| r.kind == MemoryRegionKind::Usable);
+
+    let highest_address = memory_map.iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable || is_reclaimable(r.kind))
+        .map(|r| r.end)
+        .max()
+        .expect("no usable memory regions");
+    let frame_count = Frame::containing(PhysicalAddress::new(highest_address as usize)).number();
+
+    let storage_size = PhysicalFrameAllocator::storage_words(frame_count) * core::mem::size_of::<u64>();
+    let storage_start = usable_regions()
+        .map(|r| PhysicalAddress::new(r.start as usize).align_up(VirtualMemoryManager::PAGE_SIZE)..PhysicalAddress::new(r.end as usize))
+        .find(|r| r.start.value() + storage_size <= r.end.value())
+        .expect("no usable memory region large enough for the frame allocator")
+        .start;
+
+    let storage = core::slice::from_raw_parts_mut(
+        (physical_memory_offset + storage_start.value() as u64).as_mut_ptr::<u64>(),
+        storage_size / core::mem::size_of::<u64>());
+    let mut allocator = PhysicalFrameAllocator::new(storage, frame_count);
+
+    for region in usable_regions() {
+        let start = PhysicalAddress::new(region.start as usize).align_up(VirtualMemoryManager::PAGE_SIZE);
+        let end = PhysicalAddress::new(region.end as usize).align_down(VirtualMemoryManager::PAGE_SIZE);
+        if start < end {
+            allocator.release_range(Frame::containing(start)..Frame::containing(end));
+        }
+    }
+    allocator.reserve_range(Frame::range_covering(storage_start..storage_start + storage_size));
+
+    allocator
+}
+
+/// Checks if a memory region may be reclaimed once early boot is over.
+pub(super) fn is_reclaimable(kind: MemoryRegionKind) -> bool {
+    matches!(
+        kind,
+        MemoryRegionKind::Bootloader
+            | MemoryRegionKind::UnknownUefi(UEFI_ACPI_RECLAIMABLE)
+            | MemoryRegionKind::UnknownBios(BIOS_ACPI_RECLAIMABLE))
+}
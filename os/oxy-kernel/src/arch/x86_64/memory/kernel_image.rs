@@ -0,0 +1,93 @@
+use bootloader_api::BootInfo;
+use crate::arch::prelude::*;
+use crate::memory::{Error, FlushPromise, Page, PageFlags, PhysicalAddress, VirtualAddress};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Offsets of the ELF64 file header fields we need to find the program headers.
+const E_PHOFF: usize = 0x20;
+const E_PHENTSIZE: usize = 0x36;
+const E_PHNUM: usize = 0x38;
+
+/// The size of an ELF64 program header, and the offsets of the fields we use.
+const PROGRAM_HEADER_SIZE: usize = 0x38;
+const P_TYPE: usize = 0x00;
+const P_FLAGS: usize = 0x04;
+const P_VADDR: usize = 0x10;
+const P_MEMSZ: usize = 0x28;
+
+/// A loadable part of the kernel image, as described by one of its program headers.
+struct Segment {
+    kind: u32,
+    flags: u32,
+    virtual_address: u64,
+    memory_size: u64,
+}
+
+/// Remaps every segment of the kernel image with the permissions the linker gave it.
+///
+/// The bootloader leaves the kernel's ELF file in memory, so we walk its program headers: code becomes read-only and
+/// executable, read-only data becomes read-only, and everything else becomes writable but not executable. The
+/// `PT_GNU_RELRO` segment is made read-only afterwards, since nothing relocates the (statically linked) kernel.
+///
+/// This relies on the linker placing segments with different permissions on separate pages, which it does by default.
+///
+/// # Safety
+/// `boot_info` must describe the kernel that is currently running.
+pub(super) unsafe fn protect_kernel_image(vmm: &mut VirtualMemoryManager, boot_info: &BootInfo) -> Result<(), Error> {
+    let image = vmm.physical_to_virtual(PhysicalAddress::new(boot_info.kernel_addr as usize));
+    if read::<[u8; 4]>(image, 0) != ELF_MAGIC || read::<u16>(image, E_PHENTSIZE) as usize != PROGRAM_HEADER_SIZE {
+        return Err(Error::Other("the kernel image is not a valid ELF64 file"));
+    }
+
+    let program_header_offset = read::<u64>(image, E_PHOFF) as usize;
+    let segments = (0..read::<u16>(image, E_PHNUM) as usize).map(|index| {
+        let header = image + program_header_offset + index * PROGRAM_HEADER_SIZE;
+        Segment {
+            kind: read(header, P_TYPE),
+            flags: read(header, P_FLAGS),
+            virtual_address: read(header, P_VADDR),
+            memory_size: read(header, P_MEMSZ),
+        }
+    });
+    for segment in segments.clone().filter(|s| s.kind == PT_LOAD) {
+        let flags = match (segment.flags & PF_W != 0, segment.flags & PF_X != 0) {
+            (true, true) => return Err(Error::WritableAndExecutable),
+            (true, false) => PageFlags::WRITABLE,
+            (false, true) => PageFlags::EXECUTABLE,
+            (false, false) => PageFlags::empty(),
+        };
+        protect_segment(vmm, boot_info, &segment, flags)?;
+    }
+    for segment in segments.filter(|s| s.kind == PT_GNU_RELRO) {
+        protect_segment(vmm, boot_info, &segment, PageFlags::empty())?;
+    }
+    Ok(())
+}
+
+fn protect_segment(vmm: &mut VirtualMemoryManager, boot_info: &BootInfo, segment: &Segment, flags: PageFlags) -> Result<(), Error> {
+    let start = VirtualAddress::new((boot_info.kernel_image_offset + segment.virtual_address) as usize);
+    let end = (start + segment.memory_size as usize).align_up(VirtualMemoryManager::PAGE_SIZE);
+    if !VirtualMemoryManager::KERNEL_FIXED_SPACE.contains(&start) || end > VirtualMemoryManager::KERNEL_FIXED_SPACE.end {
+        return Err(Error::Other("a kernel segment is outside of the kernel's fixed space"));
+    }
+
+    for page in Page::containing(start)..Page::containing(end) {
+        vmm.try_protect(page, flags)?.flush();
+    }
+    Ok(())
+}
+
+/// Reads a (possibly unaligned) value at `offset` bytes from `address`.
+///
+/// # Safety
+/// The memory being read must be mapped and hold a valid `T`.
+unsafe fn read<T: Copy>(address: VirtualAddress, offset: usize) -> T {
+    ((address + offset).value() as *const T).read_unaligned()
+}
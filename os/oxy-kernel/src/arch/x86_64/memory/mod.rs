@@ -1,56 +1,57 @@
-use x86_64::{PhysAddr, VirtAddr};
-use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
-use x86_64::structures::paging::mapper::MapToError;
-use crate::memory::{Error, Frame, PhysicalAddress, VirtualAddress};
+use x86_64::structures::paging::PageSize;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use crate::memory::Error;
 
 mod vmm;
-
-pub use vmm::{canonicalize_virtual_address, VirtualMemoryManager};
-
-impl Into<x86_64::structures::paging::Page> for crate::memory::Page {
-    fn into(self) -> x86_64::structures::paging::Page {
-        x86_64::structures::paging::Page::from_start_address(self.start_address().into()).unwrap()
-    }
-}
-
-impl Into<PhysicalAddress> for PhysAddr {
-    fn into(self) -> PhysicalAddress {
-        PhysicalAddress::new(self.as_u64() as usize)
-    }
-}
-
-impl Into<PhysAddr> for PhysicalAddress {
-    fn into(self) -> PhysAddr {
-        PhysAddr::new_truncate(self.value() as u64)
-    }
-}
-
-impl Into<VirtualAddress> for VirtAddr {
-    fn into(self) -> VirtualAddress { VirtualAddress::new(self.as_u64() as usize) }
-}
-
-impl Into<VirtAddr> for VirtualAddress {
-    fn into(self) -> VirtAddr { VirtAddr::new_truncate(self.value() as u64) }
+mod address_space;
+mod frame_allocator;
+mod tlb;
+mod kernel_image;
+mod paging;
+
+pub use address_space::AddressSpace;
+pub use paging::PagingMode;
+pub use tlb::{cpu_online, handle_tlb_shootdown};
+pub use vmm::VirtualMemoryManager;
+
+/// The x86_64 memory model: 4 KiB pages and 48-bit virtual addresses.
+///
+/// With 5-level paging, the processor takes 57-bit addresses, but the `x86_64` crate's addresses and mapper, which the
+/// VMM is built on, only take 48-bit ones. The kernel keeps to the addresses that are canonical either way, so the same
+/// model works whichever mode the bootloader enabled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryModel {}
+
+impl oxy_memory::MemoryModelProtocol for MemoryModel {
+    const PAGE_SIZE: usize = 4096;
+    const VIRTUAL_ADDRESS_BITS: u32 = 48;
 }
 
-impl<S: PageSize> Into<Frame> for PhysFrame<S> {
-    fn into(self) -> Frame {
-        Frame::containing(self.start_address().into())
+impl<S: PageSize> From<MapToError<S>> for Error {
+    fn from(e: MapToError<S>) -> Error {
+        match e {
+            MapToError::PageAlreadyMapped(f) => Error::PageAlreadyMapped(f.into()),
+            MapToError::FrameAllocationFailed => Error::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => Error::InsideHugePage,
+        }
     }
 }
 
-impl<S: PageSize> Into<PhysFrame<S>> for Frame {
-    fn into(self) -> PhysFrame<S> {
-        PhysFrame::containing_address(self.start_address().into())
+impl From<UnmapError> for Error {
+    fn from(e: UnmapError) -> Error {
+        match e {
+            UnmapError::PageNotMapped => Error::PageNotMapped,
+            UnmapError::ParentEntryHugePage => Error::InsideHugePage,
+            UnmapError::InvalidFrameAddress(_) => Error::Other("the page is mapped to an invalid frame address"),
+        }
     }
 }
 
-impl<S: PageSize> From<MapToError<S>> for Error {
-    fn from(e: MapToError<S>) -> Error {
+impl From<FlagUpdateError> for Error {
+    fn from(e: FlagUpdateError) -> Error {
         match e {
-            MapToError::PageAlreadyMapped(f) => Error::PageAlreadyMapped(f.into()),
-            MapToError::FrameAllocationFailed => Error::FrameAllocationFailed,
-            MapToError::ParentEntryHugePage => Error::Other("a parent entry is a huge page"),
+            FlagUpdateError::PageNotMapped => Error::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => Error::InsideHugePage,
         }
     }
 }
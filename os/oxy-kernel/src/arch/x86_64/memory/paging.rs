@@ -0,0 +1,108 @@
+use core::arch::x86_64::__cpuid_count;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+use crate::memory::Error;
+use super::VirtualMemoryManager;
+
+/// The level 5 entry whose level 4 table holds kernel space, when 5-level paging is enabled.
+///
+/// Every `KERNEL_*_SPACE` window lies in the last 256 TiB of the address space, so with 5-level paging all of the
+/// kernel's mappings are below this one entry, in an ordinary 4-level hierarchy that the `x86_64` crate's mapper can
+/// manage. The lower half of that level 4 table stays empty, so its upper half maps the same addresses as with 4-level
+/// paging.
+const KERNEL_LEVEL_5_ENTRY: usize = 511;
+
+/// The level 5 entry whose level 4 table holds user space, when 5-level paging is enabled.
+///
+/// For the same reason as [`KERNEL_LEVEL_5_ENTRY`], user space is limited to the lower 128 TiB in both modes.
+const USER_LEVEL_5_ENTRY: usize = 0;
+
+/// How many levels of page tables the processor walks, which decides how wide virtual addresses are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// 4-level paging, with 48-bit virtual addresses.
+    FourLevel,
+
+    /// 5-level paging (LA57), with 57-bit virtual addresses.
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// Gets the paging mode the processor is running in.
+    ///
+    /// LA57 can only be switched while paging is disabled, so this is whatever the bootloader chose. The kernel works
+    /// with either: it only uses addresses that are canonical in both modes, and looks up the level 4 tables through
+    /// the level 5 table when there is one.
+    pub fn current() -> Self {
+        if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+            PagingMode::FiveLevel
+        } else {
+            PagingMode::FourLevel
+        }
+    }
+
+    /// Checks if the processor supports 5-level paging.
+    pub fn is_five_level_supported() -> bool {
+        // SAFETY: CPUID is always available in long mode.
+        let features = unsafe { __cpuid_count(7, 0) };
+
+        // Bit 16 of ECX is the "LA57" feature flag.
+        features.ecx & (1 << 16) != 0
+    }
+
+    /// Gets the number of significant bits in a virtual address.
+    pub const fn virtual_address_bits(self) -> u32 {
+        match self {
+            PagingMode::FourLevel => 48,
+            PagingMode::FiveLevel => 57,
+        }
+    }
+}
+
+/// Gets the level 4 table holding kernel space, given the root table that CR3 points at.
+///
+/// # Safety
+/// `root` must be a page table root, and all of physical memory must be mapped at `physical_memory_offset`.
+pub(super) unsafe fn kernel_level_4_frame(physical_memory_offset: VirtAddr, root: PhysFrame) -> PhysFrame {
+    level_4_frame(physical_memory_offset, root, KERNEL_LEVEL_5_ENTRY)
+}
+
+/// Gets the level 4 table holding user space, given the root table that CR3 points at.
+///
+/// # Safety
+/// Same as [`kernel_level_4_frame`].
+pub(super) unsafe fn user_level_4_frame(physical_memory_offset: VirtAddr, root: PhysFrame) -> PhysFrame {
+    level_4_frame(physical_memory_offset, root, USER_LEVEL_5_ENTRY)
+}
+
+unsafe fn level_4_frame(physical_memory_offset: VirtAddr, root: PhysFrame, level_5_entry: usize) -> PhysFrame {
+    match PagingMode::current() {
+        PagingMode::FourLevel => root,
+        PagingMode::FiveLevel => {
+            let table = &*(physical_memory_offset + root.start_address().as_u64()).as_ptr::<PageTable>();
+            PhysFrame::containing_address(table[level_5_entry].addr())
+        },
+    }
+}
+
+/// Creates the root table for an address space whose user space is in `user_level_4`.
+///
+/// With 4-level paging, the level 4 table is the root. With 5-level paging, a level 5 table is allocated that points at
+/// `user_level_4` and shares the kernel's level 4 table.
+pub(super) fn new_root(vmm: &mut VirtualMemoryManager, user_level_4: PhysFrame) -> Result<PhysFrame, Error> {
+    if PagingMode::current() == PagingMode::FourLevel {
+        return Ok(user_level_4);
+    }
+
+    let root = vmm.allocate_page_table()?;
+    let (table, kernel_table) = unsafe {
+        // SAFETY: We just allocated the root, so nothing else refers to it, and we only read the kernel's.
+        (vmm.page_table_at(root), vmm.page_table_at(vmm.kernel_root()))
+    };
+    table[USER_LEVEL_5_ENTRY].set_frame(
+        user_level_4,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    table[KERNEL_LEVEL_5_ENTRY] = kernel_table[KERNEL_LEVEL_5_ENTRY].clone();
+    Ok(root)
+}
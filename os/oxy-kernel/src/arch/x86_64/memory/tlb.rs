@@ -0,0 +1,267 @@
+use core::hint::spin_loop;
+use core::iter::Step;
+use core::ops::Range;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb::{self, Pcid};
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::structures::paging::PhysFrame;
+use crate::arch::x86_64::{apic, cpu};
+use crate::arch::x86_64::interrupts::InterruptIndex;
+use crate::memory::{FlushPromise, Page};
+
+/// Above this many pages, invalidating page-by-page costs more than flushing the whole TLB.
+const FLUSH_ALL_THRESHOLD: usize = 64;
+
+/// The CPUs that are running kernel code, and so may have translations cached, as a bit mask of CPU indices.
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+/// The shootdown that other CPUs are currently being asked to perform.
+static SHOOTDOWN: Shootdown = Shootdown {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    pending: AtomicU64::new(0),
+};
+
+/// Held by the CPU whose shootdown is in [`SHOOTDOWN`], so that only one is in flight at a time.
+static SHOOTDOWN_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// The number of PCIDs the processor supports. PCID 0 is never handed out: it tags the kernel's own tables, and
+/// address spaces that didn't get a PCID of their own, and is flushed every time it is switched to.
+const PCID_COUNT: usize = 4096;
+
+/// Whether `CR4.PCIDE` is enabled, so that TLB entries are tagged with the PCID they were cached under.
+static PCIDS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The PCIDs that are in use, as a bitmap.
+static ALLOCATED_PCIDS: Spinlock<[u64; PCID_COUNT / 64]> = Spinlock::new([0; PCID_COUNT / 64]);
+
+/// For each PCID, the CPUs that may still have stale entries cached under it, as a bit mask of CPU indices.
+///
+/// Invalidating a page only affects the current PCID (and global pages), so when an address space's mappings change,
+/// the CPUs it isn't active on have to flush its PCID the next time they switch to it. A CPU's bit is only ever cleared
+/// by that flush (see [`switch_address_space`]): a shootdown can't tell which address space the pages it invalidated
+/// belonged to, so it can't vouch for the PCID the CPU happens to be running.
+static STALE_PCIDS: [AtomicU64; PCID_COUNT] = [NOT_STALE; PCID_COUNT];
+
+/// The initial value of each [`STALE_PCIDS`] entry. Only used to initialize the array.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STALE: AtomicU64 = AtomicU64::new(0);
+
+/// A request for other CPUs to invalidate a range of pages.
+struct Shootdown {
+    /// The page numbers to invalidate.
+    start: AtomicUsize,
+    end: AtomicUsize,
+
+    /// The CPUs that have yet to acknowledge the request, as a bit mask of CPU indices.
+    pending: AtomicU64,
+}
+
+/// A promise to invalidate the TLB entries for a range of pages whose mappings have changed.
+///
+/// Flushing invalidates the entries on every online CPU, not just the current one: the other CPUs are sent a
+/// shootdown interrupt, and the flush doesn't return until all of them have acknowledged it.
+///
+/// With PCIDs, entries cached for other address spaces can't be invalidated directly. Those address spaces are marked
+/// stale instead, and flushed when they are next switched to (see [`switch_address_space`]).
+#[must_use = "virtual memory changes must be flushed to take effect"]
+pub struct TlbFlush {
+    pages: Range<Page>,
+
+    /// The PCID of the only address space the pages are mapped in. `None` for kernel pages, which are global, so
+    /// invalidating them reaches every PCID, and for address spaces without a PCID of their own.
+    pcid: Option<Pcid>,
+}
+
+impl TlbFlush {
+    pub(super) fn page(page: Page) -> Self {
+        TlbFlush { pages: page..Step::forward(page, 1), pcid: None }
+    }
+
+    pub(super) fn range(pages: Range<Page>) -> Self {
+        TlbFlush { pages, pcid: None }
+    }
+
+    /// A flush that does nothing, for changes whose pages have already been invalidated everywhere.
+    pub(super) fn none() -> Self {
+        TlbFlush { pages: Page::with_number(0)..Page::with_number(0), pcid: None }
+    }
+
+    /// Limits the flush to the address space tagged with `pcid`, if it has one.
+    pub(super) fn in_address_space(self, pcid: Option<Pcid>) -> Self {
+        TlbFlush { pcid, ..self }
+    }
+}
+
+impl FlushPromise for TlbFlush {
+    fn flush(self) {
+        if self.pages.is_empty() {
+            return;
+        }
+        mark_stale(self.pcid);
+        invalidate(self.pages.start.number()..self.pages.end.number());
+    }
+}
+
+/// Enables PCIDs if the processor supports them. Must be called before any address space is created.
+pub(super) fn enable_pcids() {
+    // SAFETY: CPUID is always available in long mode.
+    let features = unsafe { __cpuid(1) };
+
+    // Bit 17 of ECX is the "PCID" feature flag. PCIDE can only be set while the current PCID is 0.
+    if features.ecx & (1 << 17) == 0 || Cr3::read_raw().1 != 0 {
+        return;
+    }
+    unsafe {
+        // SAFETY: The current PCID is 0, which is what every existing TLB entry is already tagged with.
+        Cr4::update(|flags| flags.insert(Cr4Flags::PCID));
+    }
+    PCIDS_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Enables global pages, so that kernel mappings survive address space switches. Enabling them flushes the TLB.
+pub(super) fn enable_global_pages() {
+    unsafe {
+        // SAFETY: Global entries are only cached once the flag is set, and only for pages mapped as global.
+        Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL));
+    }
+}
+
+/// Allocates a PCID for a new address space. Returns `None` if PCIDs aren't enabled, or all of them are in use.
+pub(super) fn allocate_pcid() -> Option<Pcid> {
+    if !PCIDS_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut allocated = ALLOCATED_PCIDS.lock();
+    let number = (1..PCID_COUNT).find(|&number| allocated[number / 64] & (1 << (number % 64)) == 0)?;
+    allocated[number / 64] |= 1 << (number % 64);
+
+    // Whatever the previous owner of the PCID left behind may still be cached anywhere.
+    STALE_PCIDS[number].store(u64::MAX, Ordering::Release);
+    Some(Pcid::new(number as u16).unwrap())
+}
+
+/// Returns a PCID to the pool. The address space it was allocated for must not be active on any CPU.
+pub(super) fn free_pcid(pcid: Pcid) {
+    let number = pcid.value() as usize;
+    ALLOCATED_PCIDS.lock()[number / 64] &= !(1 << (number % 64));
+}
+
+/// Marks the TLB entries cached under `pcid` as stale on every CPU.
+///
+/// CPUs that are running the address space still need to invalidate the entries themselves; this only makes sure the
+/// others flush them before they next use them. There is nothing to mark for `None`: kernel pages are global, so
+/// invalidating them reaches every PCID, and address spaces without a PCID are flushed whenever they're switched to.
+pub(super) fn mark_stale(pcid: Option<Pcid>) {
+    if let Some(pcid) = pcid {
+        STALE_PCIDS[pcid.value() as usize].store(u64::MAX, Ordering::Release);
+    }
+}
+
+/// Switches the current CPU to the page tables at `root`, tagged with `pcid`.
+///
+/// If the address space's TLB entries on this CPU are still valid, they are kept, so switching back to an address
+/// space that ran recently is cheap. Address spaces without a PCID share PCID 0, which is always flushed.
+///
+/// # Safety
+/// `root` must be a valid root page table that maps the kernel.
+pub(super) unsafe fn switch_address_space(root: PhysFrame, pcid: Option<Pcid>) {
+    match pcid {
+        Some(pcid) => {
+            let cpu = current_cpu_bit();
+            if STALE_PCIDS[pcid.value() as usize].fetch_and(!cpu, Ordering::AcqRel) & cpu != 0 {
+                Cr3::write_pcid(root, pcid);
+            } else {
+                Cr3::write_pcid_no_flush(root, pcid);
+            }
+        },
+        None => Cr3::write(root, Cr3Flags::empty()),
+    }
+}
+
+/// Flushes every TLB entry on every online CPU, including global ones.
+pub(super) fn flush_everything() {
+    invalidate(0..usize::MAX);
+}
+
+/// Marks a CPU as online, so that it takes part in TLB shootdowns.
+pub fn cpu_online(cpu: u32) {
+    assert!(cpu < u64::BITS, "CPU {} is too large to take part in TLB shootdowns", cpu);
+    ONLINE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+}
+
+/// Performs the shootdown requested of the current CPU, if there is one. Called by the shootdown interrupt handler.
+pub fn handle_tlb_shootdown() {
+    let cpu = current_cpu_bit();
+    if SHOOTDOWN.pending.load(Ordering::Acquire) & cpu != 0 {
+        invalidate_local(SHOOTDOWN.start.load(Ordering::Relaxed)..SHOOTDOWN.end.load(Ordering::Relaxed));
+        SHOOTDOWN.pending.fetch_and(!cpu, Ordering::AcqRel);
+    }
+}
+
+/// Gets the current CPU's bit in the CPU masks.
+fn current_cpu_bit() -> u64 {
+    1 << cpu::current()
+}
+
+/// Invalidates the given page numbers on every online CPU.
+fn invalidate(pages: Range<usize>) {
+    invalidate_local(pages.clone());
+
+    let others = ONLINE_CPUS.load(Ordering::Acquire) & !current_cpu_bit();
+    if others == 0 {
+        return;
+    }
+
+    without_interrupts(|| {
+        // The CPU holding the lock may be waiting for us to handle its shootdown, and we can't take the interrupt.
+        let _guard = loop {
+            match SHOOTDOWN_LOCK.try_lock() {
+                Some(guard) => break guard,
+                None => {
+                    handle_tlb_shootdown();
+                    spin_loop();
+                },
+            }
+        };
+
+        SHOOTDOWN.start.store(pages.start, Ordering::Relaxed);
+        SHOOTDOWN.end.store(pages.end, Ordering::Relaxed);
+        SHOOTDOWN.pending.store(others, Ordering::Release);
+        apic::send_ipi_to_others(InterruptIndex::TlbShootdown);
+        while SHOOTDOWN.pending.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+    });
+}
+
+/// Invalidates the given page numbers on the current CPU.
+fn invalidate_local(pages: Range<usize>) {
+    if pages.len() > FLUSH_ALL_THRESHOLD {
+        flush_local();
+    } else {
+        for number in pages {
+            tlb::flush(Page::with_number(number).start_address().into());
+        }
+    }
+}
+
+/// Flushes every TLB entry on this CPU, including global ones, and those cached under other PCIDs.
+///
+/// Reloading CR3 leaves global entries (and other PCIDs) in place, so if either is enabled we toggle `CR4.PGE`
+/// instead, which flushes everything.
+fn flush_local() {
+    let flags = Cr4::read();
+    if flags.intersects(Cr4Flags::PAGE_GLOBAL | Cr4Flags::PCID) {
+        unsafe {
+            // SAFETY: We immediately restore the original flags; toggling PGE only has the side effect we want.
+            Cr4::write(flags ^ Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(flags);
+        }
+    } else {
+        tlb::flush_all();
+    }
+}
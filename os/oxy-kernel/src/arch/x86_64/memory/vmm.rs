@@ -1,68 +1,509 @@
+use core::arch::x86_64::__cpuid;
+use core::iter::Step;
 use core::ops::Range;
 use bootloader_api::BootInfo;
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, PageSize, PageTable, PhysFrame, Size4KiB, Translate};
-use x86_64::{PhysAddr, VirtAddr};
-use x86_64::structures::paging::mapper::MapperFlush;
-use crate::memory::{Error, FlushPromise, Page, PageWritability, PhysicalAddress, VirtualAddress};
+use bootloader_api::info::MemoryRegionKind;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::structures::paging::{self as paging, FrameAllocator, Mapper, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::VirtAddr;
+use crate::arch::x86_64::memory::frame_allocator::{init_frame_allocator, is_reclaimable};
+use crate::arch::x86_64::memory::kernel_image::protect_kernel_image;
+use crate::arch::x86_64::memory::paging::{kernel_level_4_frame, user_level_4_frame};
+use crate::arch::x86_64::memory::tlb::{self, TlbFlush};
+use crate::memory::{CachePolicy, Error, FlushPromise, Frame, FrameStats, KernelLayout, Mapping, Page, PageFlags, PageSize, PhysicalAddress, PhysicalFrameAllocator, VirtualAddress, VirtualMemoryManagerProtocol};
+
+/// An OS-available page table bit marking pages whose frame was not taken from the frame allocator
+/// (see [`crate::memory::VirtualMemoryManagerProtocol::try_map_to`]) and so must not be returned to it on unmap.
+pub(super) const BORROWED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// An OS-available page table bit marking pages that share their frame with other address spaces, and were writable
+/// before they were shared. The first write to such a page faults and gets a private copy of the frame.
+pub(super) const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// The level 4 table entries that cover kernel space (the upper half of the address space).
+pub(super) const KERNEL_LEVEL_4_ENTRIES: Range<usize> = 256..512;
+
+/// The page table bits that select a [`PAT_LAYOUT`] entry.
+const CACHE_BITS: PageTableFlags = PageTableFlags::WRITE_THROUGH.union(PageTableFlags::NO_CACHE);
+
+/// The most frames [`VirtualMemoryManager::try_unmap_range`] holds on to before it invalidates the pages it has
+/// unmapped so far and frees them.
+const PENDING_FREE_LIMIT: usize = 32;
+
+/// The Page Attribute Table MSR.
+const IA32_PAT: u32 = 0x277;
+
+/// The Page Attribute Table we program at boot.
+///
+/// The power-on default has no write-combining entry, so we replace it with one where the PWT and PCD bits select:
+/// 0 = write-back, 1 (PWT) = write-combining, 2 (PCD) = write-through, 3 (PCD | PWT) = uncached.
+/// The PAT bit itself is never set, so entries 4-7 just mirror 0-3.
+const PAT_LAYOUT: u64 = 0x0004_0106_0004_0106;
 
 pub struct VirtualMemoryManager {
     mapper: OffsetPageTable<'static>,
-    frame_allocator: BootInfoFrameAllocator,
-}
 
-impl FlushPromise for MapperFlush<Size4KiB> {
-    fn flush(self) {
-        self.flush();
-    }
-}
+    /// The table CR3 points at while the kernel's own page tables are active. With 4-level paging, this is the
+    /// level 4 table the mapper manages.
+    kernel_root: PhysFrame,
 
-pub const fn canonicalize_virtual_address(addr: usize) -> usize {
-    if addr & 0x8000_0000_0000 == 0 {
-        addr
-    } else {
-        addr | 0xFFFF_8000_0000_0000
-    }
+    frame_allocator: PhysicalFrameAllocator,
+
+    /// The number of frames of each kind in the bootloader's memory map.
+    region_frames: FrameStats,
+
+    /// Whether `EFER.NXE` is enabled. The `NO_EXECUTE` bit is reserved (and faults) when it isn't.
+    no_execute_enabled: bool,
+
+    /// Whether the processor supports 1 GiB pages. 2 MiB pages are always available in long mode.
+    gigabyte_pages_supported: bool,
 }
 
 impl crate::memory::VirtualMemoryManagerProtocol for VirtualMemoryManager {
-    type FlushPromise = MapperFlush<Size4KiB>;
-    const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+    type FlushPromise = TlbFlush;
+    const PAGE_SIZE: usize = <Size4KiB as paging::PageSize>::SIZE as usize;
 
-    const VIRTUAL_ADDRESS_SPACE: Range<VirtualAddress> = VirtualAddress::new(0)..VirtualAddress::new(0xFFFF_FFFF_FFFF);
+    const VIRTUAL_ADDRESS_SPACE: Range<VirtualAddress> = VirtualAddress::new(0)..VirtualAddress::new(0xFFFF_FFFF_FFFF_FFFF);
     const PHYSICAL_ADDRESS_SPACE: Range<PhysicalAddress> = PhysicalAddress::new(0)..PhysicalAddress::new(0x1F_FFFF_FFFF_FFFF);
-    const USER_SPACE: Range<VirtualAddress> = VirtualAddress::new(0)..VirtualAddress::new(0x8000_0000_0000);
-    const KERNEL_SPACE: Range<VirtualAddress> = VirtualAddress::new(0x8000_0000_0000)..VirtualAddress::new(0xFFFF_FFFF_FFFF);
+
+    // User space is the lower 128 TiB, and kernel space the upper 128 TiB, with either paging mode (see
+    // `paging::USER_LEVEL_5_ENTRY` and `paging::KERNEL_LEVEL_5_ENTRY`).
+    const USER_SPACE: Range<VirtualAddress> = VirtualAddress::new(0)..VirtualAddress::new(0x0000_8000_0000_0000);
+    const KERNEL_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_8000_0000_0000)..VirtualAddress::new(0xFFFF_FFFF_FFFF_FFFF);
 
     // For simplicity, we use 16 TiB ranges for various address spaces.
     // This may change as I learn more about memory management, but for now it seems useful to have big, easily identifiable ranges.
-    const KERNEL_FIXED_SPACE: Range<VirtualAddress> = VirtualAddress::new(0x8000_0000_0000)..VirtualAddress::new(0x9000_0000_0000);
-    const KERNEL_HEAP_SPACE: Range<VirtualAddress> = VirtualAddress::new(0x9000_0000_0000)..VirtualAddress::new(0xA000_0000_0000);
-    const KERNEL_STACK_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xA000_0000_0000)..VirtualAddress::new(0xB000_0000_0000);
-    const KERNEL_BOOT_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xC000_0000_0000)..VirtualAddress::new(0xD000_0000_0000);
-    const KERNEL_PHYSICAL_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xD000_0000_0000)..VirtualAddress::new(0xFFFF_FFFF_FFFF);
+    // They all stay in the last 256 TiB with 5-level paging too (see `paging::KERNEL_LEVEL_5_ENTRY`).
+    const KERNEL_FIXED_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_8000_0000_0000)..VirtualAddress::new(0xFFFF_9000_0000_0000);
+    const KERNEL_HEAP_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_9000_0000_0000)..VirtualAddress::new(0xFFFF_A000_0000_0000);
+    const KERNEL_STACK_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_A000_0000_0000)..VirtualAddress::new(0xFFFF_B000_0000_0000);
+    const KERNEL_MMIO_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_B000_0000_0000)..VirtualAddress::new(0xFFFF_C000_0000_0000);
+    const KERNEL_BOOT_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_C000_0000_0000)..VirtualAddress::new(0xFFFF_D000_0000_0000);
+    const KERNEL_PHYSICAL_SPACE: Range<VirtualAddress> = VirtualAddress::new(0xFFFF_D000_0000_0000)..VirtualAddress::new(0xFFFF_FFFF_FFFF_FFFF);
 
-    fn try_map(&mut self, page: Page, writability: PageWritability) -> Result<Self::FlushPromise, Error> {
+    fn try_map(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let flags = self.page_table_flags(flags)? | kernel_space_flags(page);
         let frame = self.frame_allocator.allocate_frame().ok_or(Error::FrameAllocationFailed)?;
-        let flags = match writability {
-            PageWritability::ReadOnly => {
-                use x86_64::structures::paging::PageTableFlags;
-                PageTableFlags::PRESENT
+        let result = unsafe {
+            // SAFETY: We just allocated this frame.
+            self.mapper.map_to(page.into(), frame, flags, &mut self.frame_allocator)
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                Ok(TlbFlush::page(page))
             },
-            PageWritability::ReadWrite => {
-                use x86_64::structures::paging::PageTableFlags;
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            Err(e) => {
+                self.frame_allocator.deallocate(frame.into());
+                Err(e.into())
             },
-        };
+        }
+    }
+
+    fn try_map_zeroed(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let flags = self.page_table_flags(flags)? | kernel_space_flags(page);
+        let frame = self.frame_allocator.allocate_frame().ok_or(Error::FrameAllocationFailed)?;
         unsafe {
+            // SAFETY: We just allocated the frame, and all of physical memory is mapped at the offset.
+            let frame_ptr = (self.physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+            frame_ptr.write_bytes(0, Self::PAGE_SIZE);
+        }
+
+        let result = unsafe {
             // SAFETY: We just allocated this frame.
-            Ok(self.mapper.map_to(page.into(), frame, flags, &mut self.frame_allocator)?)
+            self.mapper.map_to(page.into(), frame, flags, &mut self.frame_allocator)
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                Ok(TlbFlush::page(page))
+            },
+            Err(e) => {
+                self.frame_allocator.deallocate(frame.into());
+                Err(e.into())
+            },
         }
     }
 
+    unsafe fn try_map_to(&mut self, page: Page, frame: Frame, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let flags = self.page_table_flags(flags)? | kernel_space_flags(page) | BORROWED_FRAME;
+        let frame: PhysFrame = frame.into();
+        self.mapper.map_to(page.into(), frame, flags, &mut self.frame_allocator)?.ignore();
+        Ok(TlbFlush::page(page))
+    }
+
+    fn try_map_range(&mut self, pages: Range<Page>, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let table_flags = self.page_table_flags(flags)? | kernel_space_flags(pages.start);
+        let mut page = pages.start;
+        while page < pages.end {
+            let result = self.map_next_allocated(page..pages.end, table_flags, flags.contains(PageFlags::HUGE));
+            match result {
+                Ok(size) => page = Step::forward(page, size.pages()),
+                Err(e) => {
+                    // Roll back the pages we've already mapped so that a failure doesn't leave the range half-mapped.
+                    self.try_unmap_range(pages.start..page).expect("failed to roll back pages we just mapped").flush();
+                    return Err(e);
+                },
+            }
+        }
+        Ok(TlbFlush::range(pages))
+    }
+
+    unsafe fn try_map_range_to(&mut self, pages: Range<Page>, first_frame: Frame, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let table_flags = self.page_table_flags(flags)? | kernel_space_flags(pages.start) | BORROWED_FRAME;
+        let mut page = pages.start;
+        while page < pages.end {
+            let offset = Step::steps_between(&pages.start, &page).unwrap();
+            let frame = Step::forward(first_frame, offset);
+            let size = if flags.contains(PageFlags::HUGE) {
+                self.largest_page_size(page..pages.end, Some(frame))
+            } else {
+                PageSize::Size4KiB
+            };
+            if let Err(e) = self.map_sized(page, frame, size, table_flags) {
+                self.try_unmap_range(pages.start..page).expect("failed to roll back pages we just mapped").flush();
+                return Err(e);
+            }
+            page = Step::forward(page, size.pages());
+        }
+        Ok(TlbFlush::range(pages))
+    }
+
+    fn try_unmap(&mut self, page: Page) -> Result<Self::FlushPromise, Error> {
+        self.try_unmap_range(page..Step::forward(page, 1))
+    }
+
+    fn try_unmap_range(&mut self, pages: Range<Page>) -> Result<Self::FlushPromise, Error> {
+        // Check the whole range first, so that a failure doesn't leave it half-unmapped.
+        let mut page = pages.start;
+        while page < pages.end {
+            let (_, size, _) = self.mapping(page)?;
+            page = Step::forward(page, size.pages());
+        }
+
+        // Another CPU may still reach an unmapped frame through its TLB, so frames are held on to until the pages they
+        // were mapped at have been invalidated everywhere, and only then freed.
+        let mut pending = [(Frame::containing(PhysicalAddress::new(0)), PageSize::Size4KiB); PENDING_FREE_LIMIT];
+        let mut pending_count = 0;
+        let mut unflushed = pages.start;
+        page = pages.start;
+        while page < pages.end {
+            let (frame, size, flags) = self.mapping(page).expect("the range was checked above");
+            unsafe {
+                // SAFETY: The caller is giving up the page, and its frame isn't freed until it has been invalidated.
+                self.unmap_sized(page, size).expect("the range was checked above");
+            }
+            page = Step::forward(page, size.pages());
+            if !flags.contains(BORROWED_FRAME) {
+                pending[pending_count] = (frame, size);
+                pending_count += 1;
+            }
+            if pending_count == PENDING_FREE_LIMIT || (pending_count > 0 && page >= pages.end) {
+                TlbFlush::range(unflushed..page).flush();
+                for &(frame, size) in &pending[..pending_count] {
+                    self.free_frames(frame, size);
+                }
+                pending_count = 0;
+                unflushed = page;
+            }
+        }
+
+        // Whatever is left only mapped borrowed frames, so it is safe to leave the flush to the caller.
+        Ok(TlbFlush::range(unflushed..page))
+    }
+
+    fn try_protect(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error> {
+        let (_, size, old_flags) = self.mapping(page)?;
+
+        // The ownership bit belongs to the VMM, not the caller, so it has to be preserved.
+        let mut flags = self.page_table_flags(flags)? | kernel_space_flags(page) | (old_flags & BORROWED_FRAME);
+
+        // A shared page must stay read-only until it has been copied, even if it is being made writable.
+        if old_flags.contains(COPY_ON_WRITE) && flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+        }
+        unsafe {
+            // SAFETY: Changing flags can't violate memory safety on its own; callers that rely on a page being
+            // writable are expected to hold the memory they're writing to.
+            match size {
+                PageSize::Size4KiB => self.mapper.update_flags(x86_page::<Size4KiB>(page), flags)?.ignore(),
+                PageSize::Size2MiB => self.mapper.update_flags(x86_page::<Size2MiB>(page), flags)?.ignore(),
+                PageSize::Size1GiB => self.mapper.update_flags(x86_page::<Size1GiB>(page), flags)?.ignore(),
+            }
+        }
+        Ok(TlbFlush::range(page..Step::forward(page, size.pages())))
+    }
+
+    fn try_resolve_copy_on_write(&mut self, page: Page) -> Result<Self::FlushPromise, Error> {
+        let mut mapper = unsafe {
+            // SAFETY: The VMM is locked, so nothing else is changing the active page tables.
+            let level_4_frame = user_level_4_frame(self.physical_memory_offset(), Cr3::read().0);
+            OffsetPageTable::new(self.page_table_at(level_4_frame), self.physical_memory_offset())
+        };
+        resolve_copy_on_write(self, &mut mapper, page)
+    }
+
+    fn try_allocate_contiguous(&mut self, count: usize, align: usize, limit: PhysicalAddress) -> Result<Frame, Error> {
+        self.frame_allocator
+            .allocate_contiguous_below(count, align, Frame::containing(limit))
+            .ok_or(Error::FrameAllocationFailed)
+    }
+
+    fn deallocate_contiguous(&mut self, frames: Range<Frame>) {
+        for frame in frames {
+            self.frame_allocator.deallocate(frame);
+        }
+    }
+
+    fn frame_stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.frame_allocator.total_frames(),
+            free: self.frame_allocator.free_frames(),
+            ..self.region_frames.clone()
+        }
+    }
+
+    fn mapped_pages(&self, range: Range<VirtualAddress>) -> usize {
+        count_mapped_pages(self, self.mapper.level_4_table(), 4, 0, &range)
+    }
+
+    fn page_table_frames(&self) -> usize {
+        count_page_tables(self, self.mapper.level_4_table(), 4)
+    }
+
+    fn walk_mappings(&self, visit: &mut dyn FnMut(Mapping)) {
+        walk_mappings(self, self.mapper.level_4_table(), 4, 0, visit);
+    }
+
     fn virtual_to_physical(&self, addr: VirtualAddress) -> Option<PhysicalAddress> {
         self.mapper.translate_addr(addr.into()).map(|a| a.into())
     }
+
+    fn physical_to_virtual(&self, addr: PhysicalAddress) -> VirtualAddress {
+        VirtualAddress::new(self.mapper.phys_offset().as_u64() as usize) + addr.value()
+    }
+}
+
+/// Gives a copy-on-write page in the tables of `mapper` a frame of its own, and makes it writable.
+pub(super) fn resolve_copy_on_write(vmm: &mut VirtualMemoryManager, mapper: &mut OffsetPageTable, page: Page) -> Result<TlbFlush, Error> {
+    let (frame, flags) = match mapper.translate(page.start_address().into()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(Error::AccessViolation),
+        _ => return Err(Error::PageNotMapped),
+    };
+    if !flags.contains(COPY_ON_WRITE) {
+        // Another processor may have resolved the fault first, leaving us with a stale TLB entry.
+        return if flags.contains(PageTableFlags::WRITABLE) {
+            Ok(TlbFlush::page(page))
+        } else {
+            Err(Error::AccessViolation)
+        };
+    }
+
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if vmm.frame_allocator.references(frame.into()) == 1 {
+        // Every other address space has let go of the frame, so there is nothing left to copy.
+        unsafe {
+            // SAFETY: The page is only mapped by this address space.
+            mapper.update_flags(page.into(), flags)?.ignore();
+        }
+        return Ok(TlbFlush::page(page));
+    }
+
+    let copy = vmm.frame_allocator.allocate_frame().ok_or(Error::FrameAllocationFailed)?;
+    unsafe {
+        // SAFETY: We just allocated the copy, and all of physical memory is mapped at the offset.
+        let source = (vmm.physical_memory_offset() + frame.start_address().as_u64()).as_ptr::<u8>();
+        let destination = (vmm.physical_memory_offset() + copy.start_address().as_u64()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(source, destination, VirtualMemoryManager::PAGE_SIZE);
+
+        // SAFETY: The copy is ours, and the page table entries leading to the page already exist, so remapping it
+        // can't fail.
+        mapper.unmap(page.into())?.1.ignore();
+        mapper.map_to(page.into(), copy, flags, &mut vmm.frame_allocator)?.ignore();
+    }
+
+    // Drop this address space's reference to the shared frame.
+    vmm.frame_allocator.deallocate(frame.into());
+    Ok(TlbFlush::page(page))
+}
+
+/// Counts the frames of each kind in the bootloader's memory map.
+fn count_region_frames(boot_info: &BootInfo) -> FrameStats {
+    let mut stats = FrameStats::default();
+    for region in boot_info.memory_regions.iter() {
+        let frames = (region.end - region.start) as usize / VirtualMemoryManager::PAGE_SIZE;
+        match region.kind {
+            MemoryRegionKind::Usable => stats.usable += frames,
+            MemoryRegionKind::Bootloader => stats.bootloader += frames,
+            _ => stats.firmware += frames,
+        }
+    }
+    stats
+}
+
+/// Gets the page table flags that every mapping of `page` in the kernel's tables gets, on top of the ones asked for.
+///
+/// Kernel space is shared by every address space, so its pages are global: their TLB entries survive address space
+/// switches, and invalidating one invalidates it under every PCID.
+fn kernel_space_flags(page: Page) -> PageTableFlags {
+    if VirtualMemoryManager::KERNEL_SPACE.contains(&page.start_address()) {
+        PageTableFlags::GLOBAL
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Marks every page mapped by a page table of the given level as global.
+fn make_global(vmm: &VirtualMemoryManager, table_frame: PhysFrame, level: u32) {
+    let table = unsafe {
+        // SAFETY: The frame holds a kernel page table, and the VMM is still being created, so nothing else uses it.
+        vmm.page_table_at(table_frame)
+    };
+    for entry in table.iter_mut().filter(|e| e.flags().contains(PageTableFlags::PRESENT)) {
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            entry.set_flags(entry.flags() | PageTableFlags::GLOBAL);
+        } else {
+            make_global(vmm, PhysFrame::containing_address(entry.addr()), level - 1);
+        }
+    }
+}
+
+/// Unlinks the tables below a page table of the given level, whose first entry maps `base`, that cover part of `range`
+/// and no longer map anything. Returns whether the table itself maps nothing.
+fn unlink_empty_tables(vmm: &VirtualMemoryManager, table_frame: PhysFrame, level: u32, base: usize, range: &Range<VirtualAddress>) -> bool {
+    let table = unsafe {
+        // SAFETY: The frame holds a kernel page table, and `&mut self` on the caller keeps anything else from using it.
+        vmm.page_table_at(table_frame)
+    };
+    let span = 1usize << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        let start = VirtualAddress::new(base + index * span);
+        let last = start.value() + (span - 1);
+        let is_table = level > 1
+            && entry.flags().contains(PageTableFlags::PRESENT)
+            && !entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if is_table
+            && start < range.end
+            && last >= range.start.value()
+            && unlink_empty_tables(vmm, PhysFrame::containing_address(entry.addr()), level - 1, start.value(), range)
+        {
+            entry.set_unused();
+        }
+    }
+    table.iter().all(|entry| entry.is_unused())
+}
+
+/// Reserves a page table of the given level, whose first entry maps `base`, and every frame mapped through it.
+///
+/// Borrowed frames and the physical memory map (`skip`) don't own the frames they point at, so they're left alone.
+fn reserve_referenced_frames(vmm: &mut VirtualMemoryManager, table_frame: PhysFrame, level: u32, base: usize, skip: &Range<VirtualAddress>) {
+    vmm.frame_allocator.reserve_range(Frame::from(table_frame)..Step::forward(Frame::from(table_frame), 1));
+    let table = unsafe {
+        // SAFETY: The frame holds a page table, and we only read it.
+        vmm.page_table_at(table_frame)
+    };
+    let span = 1usize << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = VirtualAddress::new(base + index * span);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if !entry.flags().contains(BORROWED_FRAME) && !skip.contains(&start) {
+                let frame = Frame::containing(entry.addr().into());
+                vmm.frame_allocator.reserve_range(frame..Step::forward(frame, span / VirtualMemoryManager::PAGE_SIZE));
+            }
+        } else {
+            reserve_referenced_frames(vmm, PhysFrame::containing_address(entry.addr()), level - 1, start.value(), skip);
+        }
+    }
+}
+
+/// Counts the 4 KiB pages mapped in `range` by a page table of the given level, whose first entry maps `base`.
+fn count_mapped_pages(vmm: &VirtualMemoryManager, table: &PageTable, level: u32, base: usize, range: &Range<VirtualAddress>) -> usize {
+    let span = 1usize << (12 + 9 * (level - 1));
+    table.iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.is_unused())
+        .map(|(index, entry)| {
+            let start = VirtualAddress::new(base + index * span);
+            let last = start.value() + (span - 1);
+            if start >= range.end || last < range.start.value() {
+                0
+            } else if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // A huge page may only partly overlap the range, and only the pages that start in the range count.
+                let first = start.value().max(range.start.value()).next_multiple_of(VirtualMemoryManager::PAGE_SIZE);
+                let end = last.min(range.end.value() - 1);
+                if first > end { 0 } else { (end - first) / VirtualMemoryManager::PAGE_SIZE + 1 }
+            } else {
+                let table = unsafe {
+                    // SAFETY: The entry points at a page table, and we only read it.
+                    vmm.page_table_at(PhysFrame::containing_address(entry.addr()))
+                };
+                count_mapped_pages(vmm, table, level - 1, start.value(), range)
+            }
+        })
+        .sum()
+}
+
+/// Visits every present mapping in a page table of the given level, whose first entry maps `base`.
+pub(super) fn walk_mappings(vmm: &VirtualMemoryManager, table: &PageTable, level: u32, base: usize, visit: &mut dyn FnMut(Mapping)) {
+    let span = 1usize << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = VirtualAddress::new(base + index * span);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            visit(Mapping {
+                page: Page::containing(start),
+                frame: Frame::containing(entry.addr().into()),
+                size: match level {
+                    3 => PageSize::Size1GiB,
+                    2 => PageSize::Size2MiB,
+                    _ => PageSize::Size4KiB,
+                },
+                flags: vmm.page_flags(entry.flags()),
+            });
+        } else {
+            let table = unsafe {
+                // SAFETY: The entry points at a page table, and we only read it.
+                vmm.page_table_at(PhysFrame::containing_address(entry.addr()))
+            };
+            walk_mappings(vmm, table, level - 1, start.value(), visit);
+        }
+    }
+}
+
+/// Counts a page table of the given level, and every page table below it.
+fn count_page_tables(vmm: &VirtualMemoryManager, table: &PageTable, level: u32) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    let children: usize = table.iter()
+        .filter(|entry| !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .map(|entry| {
+            let table = unsafe {
+                // SAFETY: The entry points at a page table, and we only read it.
+                vmm.page_table_at(PhysFrame::containing_address(entry.addr()))
+            };
+            count_page_tables(vmm, table, level - 1)
+        })
+        .sum();
+    children + 1
+}
+
+/// Converts a [`CachePolicy`] into the page table flags that select the matching entry of [`PAT_LAYOUT`].
+fn cache_flags(cache: CachePolicy) -> PageTableFlags {
+    match cache {
+        CachePolicy::WriteBack => PageTableFlags::empty(),
+        CachePolicy::WriteCombining => PageTableFlags::WRITE_THROUGH,
+        CachePolicy::WriteThrough => PageTableFlags::NO_CACHE,
+        CachePolicy::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    }
 }
 
 impl VirtualMemoryManager {
@@ -70,21 +511,367 @@ impl VirtualMemoryManager {
     ///
     /// # Parameters
     /// * `boot_info` - The bootloader's boot information.
-    pub fn new(boot_info: &'static BootInfo) -> Self {
-        let physical_memory_offset = VirtAddr::new(*boot_info.physical_memory_offset.as_ref().unwrap());
+    /// * `layout` - Where physical memory should be mapped. If the bootloader mapped it somewhere else, it is moved.
+    pub fn new(boot_info: &'static BootInfo, layout: &KernelLayout) -> Self {
+        let physical_memory_offset = *boot_info.physical_memory_offset.as_ref().unwrap();
+        // With 5-level paging, the bootloader could put the map outside the addresses the kernel can represent.
+        assert!(
+            Self::KERNEL_SPACE.start.value() as u64 <= physical_memory_offset,
+            "the bootloader mapped physical memory outside kernel space");
+        let physical_memory_offset = VirtAddr::new(physical_memory_offset);
         let mapper = unsafe {
             get_page_table(physical_memory_offset)
         };
         let frame_allocator = unsafe {
-            BootInfoFrameAllocator::init(&boot_info.memory_regions)
+            init_frame_allocator(&boot_info.memory_regions, physical_memory_offset)
         };
-        VirtualMemoryManager {
+        unsafe {
+            // SAFETY: Nothing mapped so far uses any PAT entry other than 0 (write-back), which we leave unchanged.
+            Msr::new(IA32_PAT).write(PAT_LAYOUT);
+
+            // SAFETY: Without this, the kernel can write to read-only pages, which would make W^X pointless.
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        }
+
+        // SAFETY: CPUID is always available in long mode.
+        let extended_features = unsafe { __cpuid(0x8000_0001) };
+
+        // Bit 20 of EDX is the "NX" feature flag. Nothing can have the NO_EXECUTE bit set yet (it is reserved until
+        // NXE is enabled), so enabling it doesn't change any existing mapping.
+        if extended_features.edx & (1 << 20) != 0 {
+            unsafe {
+                // SAFETY: The processor supports it, and see above.
+                Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+            }
+        }
+
+        // No address space exists yet, so nothing has been tagged with a PCID other than 0.
+        tlb::enable_pcids();
+
+        let mut vmm = VirtualMemoryManager {
             mapper,
+            kernel_root: Cr3::read().0,
             frame_allocator,
+            region_frames: count_region_frames(boot_info),
+            no_execute_enabled: Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE),
+            // Bit 26 of EDX is the "Page1GB" feature flag.
+            gigabyte_pages_supported: extended_features.edx & (1 << 26) != 0,
+        };
+        vmm.populate_kernel_tables().expect("Failed to allocate kernel page tables");
+
+        // The bootloader's kernel mappings aren't global, and enabling global pages flushes any it already cached.
+        for index in KERNEL_LEVEL_4_ENTRIES {
+            make_global(&vmm, PhysFrame::containing_address(vmm.kernel_level_4_table()[index].addr()), 3);
+        }
+        tlb::enable_global_pages();
+        unsafe {
+            // SAFETY: The boot info comes from the bootloader that loaded us.
+            protect_kernel_image(&mut vmm, boot_info).expect("Failed to protect the kernel image");
+        }
+        if layout.physical_memory != vmm.physical_to_virtual(PhysicalAddress::new(0)) {
+            unsafe {
+                // SAFETY: Nothing has been handed a pointer into the physical memory map yet, other than the frame
+                // allocator, which is moved along with it.
+                vmm.relocate_physical_memory(layout.physical_memory, Self::physical_memory_size(boot_info))
+                    .expect("Failed to move the physical memory map");
+            }
+        }
+        vmm
+    }
+
+    /// Gets the size of the physical memory map the bootloader creates: everything up to the end of the highest
+    /// region in the memory map, rounded up to a 2 MiB page.
+    pub fn physical_memory_size(boot_info: &BootInfo) -> usize {
+        let end = boot_info.memory_regions.iter().map(|region| region.end).max().unwrap_or(0);
+        PhysicalAddress::new(end as usize).align_up(PageSize::Size2MiB.bytes()).value()
+    }
+
+    /// Returns the memory used by the bootloader and the firmware's ACPI tables to the frame allocator.
+    ///
+    /// Frames that are still mapped (the kernel image, the boot stack, the boot info, and the page tables themselves)
+    /// are kept. Returns the number of frames reclaimed.
+    ///
+    /// # Safety
+    /// Nothing may read the ACPI tables through the physical memory map afterwards, nor anything else the bootloader
+    /// left in memory without mapping it for us.
+    pub unsafe fn reclaim_boot_memory(&mut self, boot_info: &BootInfo) -> usize {
+        let free_before = self.frame_allocator.free_frames();
+        for region in boot_info.memory_regions.iter().filter(|region| is_reclaimable(region.kind)) {
+            let start = PhysicalAddress::new(region.start as usize).align_up(Self::PAGE_SIZE);
+            let end = PhysicalAddress::new(region.end as usize).align_down(Self::PAGE_SIZE);
+            if start < end {
+                self.frame_allocator.release_range(Frame::containing(start)..Frame::containing(end));
+            }
+        }
+
+        // Releasing everything first and then taking back what is still referenced saves us from having to keep
+        // track of the referenced frames somewhere. Nothing can allocate in between, since we hold `&mut self`.
+        let physical_start = self.physical_to_virtual(PhysicalAddress::new(0));
+        let physical_memory = physical_start..physical_start + Self::physical_memory_size(boot_info);
+        let level_4_frame = kernel_level_4_frame(self.physical_memory_offset(), self.kernel_root);
+        if level_4_frame != self.kernel_root {
+            self.frame_allocator.reserve_range(Frame::from(self.kernel_root)..Step::forward(Frame::from(self.kernel_root), 1));
+        }
+        reserve_referenced_frames(self, level_4_frame, 4, 0, &physical_memory);
+
+        let reclaimed = self.frame_allocator.free_frames() - free_before;
+        self.region_frames.reclaimed += reclaimed;
+        reclaimed
+    }
+
+    /// Maps the first `size` bytes of physical memory at `start` instead of where the bootloader mapped them, and
+    /// removes the bootloader's mapping.
+    ///
+    /// # Safety
+    /// Nothing but the page tables and the frame allocator may be using the old mapping.
+    unsafe fn relocate_physical_memory(&mut self, start: VirtualAddress, size: usize) -> Result<(), Error> {
+        let old_start = self.physical_to_virtual(PhysicalAddress::new(0));
+        let new_pages = Page::containing(start)..Page::containing(start + size);
+        if Page::containing(old_start + size) > new_pages.start && new_pages.end > Page::containing(old_start) {
+            return Err(Error::AreaOverlaps);
+        }
+
+        self.try_map_range_to(new_pages, Frame::containing(PhysicalAddress::new(0)), PageFlags::WRITABLE | PageFlags::HUGE)?.flush();
+        let level_4_frame = kernel_level_4_frame(self.physical_memory_offset(), self.kernel_root);
+        let offset = VirtAddr::new(start.value() as u64);
+        self.mapper = OffsetPageTable::new(&mut *(offset + level_4_frame.start_address().as_u64()).as_mut_ptr(), offset);
+        self.frame_allocator.relocate_storage(start.value().wrapping_sub(old_start.value()) as isize);
+
+        // The bootloader's mapping doesn't own its frames, so they're forgotten rather than freed.
+        let old_end = Page::containing(old_start + size);
+        let mut page = Page::containing(old_start);
+        while page < old_end {
+            let size = match self.mapping(page) {
+                Ok((_, size, _)) => size,
+                Err(Error::PageNotMapped) => {
+                    page = Step::forward(page, 1);
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            self.unmap_sized(page, size)?;
+            page = Step::forward(page, size.pages());
+        }
+
+        // The tables that held the old mapping are empty now. The bootloader built them in its own memory, so once
+        // they're unlinked, `reclaim_boot_memory` returns them with the rest of it. The level 3 tables stay, since
+        // every address space shares them.
+        let old_range = old_start..old_start + size;
+        for index in KERNEL_LEVEL_4_ENTRIES {
+            let frame = PhysFrame::containing_address(self.kernel_level_4_table()[index].addr());
+            unlink_empty_tables(self, frame, 3, index << 39, &old_range);
+        }
+        TlbFlush::range(Page::containing(old_start)..old_end).flush();
+        Ok(())
+    }
+
+    /// Makes sure every level 4 entry covering [`crate::memory::VirtualMemoryManagerProtocol::KERNEL_SPACE`] points at a
+    /// level 3 table.
+    ///
+    /// Address spaces copy the kernel's level 4 entries when they're created. As long as those entries never change
+    /// afterwards, every kernel mapping made through the shared level 3 tables is visible in every address space.
+    fn populate_kernel_tables(&mut self) -> Result<(), Error> {
+        for index in KERNEL_LEVEL_4_ENTRIES {
+            if self.mapper.level_4_table()[index].is_unused() {
+                let frame = self.allocate_page_table()?;
+                self.mapper.level_4_table_mut()[index].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates a frame for a page table and zeroes it.
+    pub(super) fn allocate_page_table(&mut self) -> Result<PhysFrame, Error> {
+        let frame: PhysFrame = self.frame_allocator.allocate().ok_or(Error::FrameAllocationFailed)?.into();
+        unsafe {
+            // SAFETY: We own the frame we just allocated, and all of physical memory is mapped at the offset.
+            self.page_table_at(frame).zero();
+        }
+        Ok(frame)
+    }
+
+    /// Gets the page table stored in `frame`, through the physical memory mapping.
+    ///
+    /// # Safety
+    /// `frame` must hold a page table, and the caller must make sure the returned reference isn't aliased.
+    pub(super) unsafe fn page_table_at(&self, frame: PhysFrame) -> &'static mut PageTable {
+        &mut *(self.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr::<PageTable>()
+    }
+
+    /// Gets the root table of the kernel's page tables, whose kernel half every address space shares.
+    pub(super) fn kernel_root(&self) -> PhysFrame {
+        self.kernel_root
+    }
+
+    /// Gets the kernel's level 4 page table, whose upper half every address space shares.
+    pub(super) fn kernel_level_4_table(&self) -> &PageTable {
+        self.mapper.level_4_table()
+    }
+
+    pub(super) fn physical_memory_offset(&self) -> VirtAddr {
+        self.mapper.phys_offset()
+    }
+
+    pub(super) fn frame_allocator(&mut self) -> &mut PhysicalFrameAllocator {
+        &mut self.frame_allocator
+    }
+
+    /// Picks the largest page size that can be used to map the start of `pages` without running past its end.
+    ///
+    /// If the pages are being mapped to a specific frame, that frame must be aligned to the page size as well.
+    fn largest_page_size(&self, pages: Range<Page>, frame: Option<Frame>) -> PageSize {
+        let remaining = Step::steps_between(&pages.start, &pages.end).unwrap_or(0);
+        [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .filter(|&size| size != PageSize::Size1GiB || self.gigabyte_pages_supported)
+            .find(|&size| {
+                pages.start.is_aligned(size.bytes())
+                    && frame.map_or(true, |f| f.is_aligned(size.bytes()))
+                    && remaining >= size.pages()
+            })
+            .unwrap_or(PageSize::Size4KiB)
+    }
+
+    /// Maps the start of `pages` to newly allocated frames, and returns the size of page that was used.
+    ///
+    /// If `huge` is set, that is the largest page size we can get contiguous frames for; otherwise it is always 4 KiB.
+    fn map_next_allocated(&mut self, pages: Range<Page>, flags: PageTableFlags, huge: bool) -> Result<PageSize, Error> {
+        let mut size = if huge {
+            self.largest_page_size(pages.clone(), None)
+        } else {
+            PageSize::Size4KiB
+        };
+        let frame = loop {
+            let frame = match size {
+                PageSize::Size4KiB => self.frame_allocator.allocate(),
+                _ => self.frame_allocator.allocate_contiguous(size.pages(), size.pages()),
+            };
+            match (frame, size) {
+                (Some(frame), _) => break frame,
+                (None, PageSize::Size4KiB) => return Err(Error::FrameAllocationFailed),
+                (None, PageSize::Size2MiB) => size = PageSize::Size4KiB,
+                (None, PageSize::Size1GiB) => size = PageSize::Size2MiB,
+            }
+        };
+
+        let result = unsafe {
+            // SAFETY: We just allocated these frames.
+            self.map_sized(pages.start, frame, size, flags)
+        };
+        if result.is_err() {
+            self.free_frames(frame, size);
+        }
+        result.map(|_| size)
+    }
+
+    /// Maps a single page of the given size.
+    ///
+    /// # Safety
+    /// Same as [`crate::memory::VirtualMemoryManagerProtocol::try_map_to`].
+    unsafe fn map_sized(&mut self, page: Page, frame: Frame, size: PageSize, flags: PageTableFlags) -> Result<(), Error> {
+        match size {
+            PageSize::Size4KiB => self.map_with::<Size4KiB>(page, frame, flags),
+            PageSize::Size2MiB => self.map_with::<Size2MiB>(page, frame, flags),
+            PageSize::Size1GiB => self.map_with::<Size1GiB>(page, frame, flags),
+        }
+    }
+
+    unsafe fn map_with<S: paging::PageSize>(&mut self, page: Page, frame: Frame, flags: PageTableFlags) -> Result<(), Error>
+    where OffsetPageTable<'static>: Mapper<S>
+    {
+        let frame = PhysFrame::<S>::from_start_address(frame.start_address().into()).unwrap();
+        self.mapper.map_to(x86_page::<S>(page), frame, flags, &mut self.frame_allocator)?.ignore();
+        Ok(())
+    }
+
+    /// Removes the mapping of the given size that starts at `page`, without freeing its frames.
+    ///
+    /// # Safety
+    /// Nothing may rely on the page being mapped anymore.
+    unsafe fn unmap_sized(&mut self, page: Page, size: PageSize) -> Result<(), Error> {
+        match size {
+            PageSize::Size4KiB => self.mapper.unmap(x86_page::<Size4KiB>(page))?.1.ignore(),
+            PageSize::Size2MiB => self.mapper.unmap(x86_page::<Size2MiB>(page))?.1.ignore(),
+            PageSize::Size1GiB => self.mapper.unmap(x86_page::<Size1GiB>(page))?.1.ignore(),
+        }
+        Ok(())
+    }
+
+    fn free_frames(&mut self, first_frame: Frame, size: PageSize) {
+        for frame in first_frame..Step::forward(first_frame, size.pages()) {
+            self.frame_allocator.deallocate(frame);
+        }
+    }
+
+    /// Converts [`PageFlags`] into the page table flags used to map a present page, enforcing W^X.
+    pub(super) fn page_table_flags(&self, flags: PageFlags) -> Result<PageTableFlags, Error> {
+        if flags.contains(PageFlags::WRITABLE | PageFlags::EXECUTABLE) {
+            return Err(Error::WritableAndExecutable);
+        }
+
+        let mut result = PageTableFlags::PRESENT | cache_flags(flags.cache_policy());
+        if flags.contains(PageFlags::WRITABLE) {
+            result |= PageTableFlags::WRITABLE;
+        }
+        if !flags.contains(PageFlags::EXECUTABLE) && self.no_execute_enabled {
+            result |= PageTableFlags::NO_EXECUTE;
+        }
+        if flags.contains(PageFlags::USER) {
+            result |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if flags.contains(PageFlags::GLOBAL) {
+            result |= PageTableFlags::GLOBAL;
+        }
+        Ok(result)
+    }
+
+    /// Converts the page table flags of a present page back into [`PageFlags`].
+    pub(super) fn page_flags(&self, flags: PageTableFlags) -> PageFlags {
+        let mut result = PageFlags::empty();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            result |= PageFlags::WRITABLE;
+        }
+        if !flags.contains(PageTableFlags::NO_EXECUTE) {
+            result |= PageFlags::EXECUTABLE;
+        }
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            result |= PageFlags::USER;
+        }
+        if flags.contains(PageTableFlags::GLOBAL) {
+            result |= PageFlags::GLOBAL;
+        }
+        let cache = [CachePolicy::WriteBack, CachePolicy::WriteCombining, CachePolicy::WriteThrough, CachePolicy::Uncached]
+            .into_iter()
+            .find(|&cache| flags & CACHE_BITS == cache_flags(cache))
+            .unwrap_or(CachePolicy::WriteBack);
+        result.with_cache_policy(cache)
+    }
+
+    /// Gets the first frame, size and flags of the mapping that starts at `page`.
+    ///
+    /// Fails with [`Error::InsideHugePage`] if `page` is mapped, but isn't the start of its (huge) page.
+    fn mapping(&self, page: Page) -> Result<(Frame, PageSize, PageTableFlags), Error> {
+        match self.mapper.translate(page.start_address().into()) {
+            TranslateResult::Mapped { offset, .. } if offset != 0 => Err(Error::InsideHugePage),
+            TranslateResult::Mapped { frame, flags, .. } => {
+                let size = match frame {
+                    MappedFrame::Size4KiB(_) => PageSize::Size4KiB,
+                    MappedFrame::Size2MiB(_) => PageSize::Size2MiB,
+                    MappedFrame::Size1GiB(_) => PageSize::Size1GiB,
+                };
+                Ok((Frame::containing(frame.start_address().into()), size, flags))
+            },
+            TranslateResult::NotMapped => Err(Error::PageNotMapped),
+            TranslateResult::InvalidFrameAddress(_) => Err(Error::Other("the page is mapped to an invalid frame address")),
         }
     }
 }
 
+/// Converts a [`Page`] that is aligned to `S` into the x86_64 crate's page type.
+fn x86_page<S: paging::PageSize>(page: Page) -> paging::Page<S> {
+    paging::Page::from_start_address(page.start_address().into()).unwrap()
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -106,9 +893,7 @@ pub unsafe fn get_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTabl
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
                                -> &'static mut PageTable
 {
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
+    let level_4_table_frame = kernel_level_4_frame(physical_memory_offset, Cr3::read().0);
 
     let phys = level_4_table_frame.start_address();
     let virt = physical_memory_offset + phys.as_u64();
@@ -116,50 +901,3 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 
     &mut *page_table_ptr // unsafe
 }
-
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // TODO: Doing this isn't optimal.
-        // We build the iterator each time we need a new frame.
-        // Ideally we'd either store the iterator (but we can't until https://github.com/rust-lang/rfcs/blob/master/text/2071-impl-trait-existential-types.md)
-
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
@@ -1,15 +1,23 @@
 mod acpi;
+mod apic;
+mod cpu;
 pub mod memory;
 mod interrupts;
 mod gdt;
 mod early;
 mod entry;
+mod backtrace;
+mod user_access;
+
+pub use backtrace::return_addresses;
+pub use cpu::current as current_cpu;
+pub use user_access::copy_user_bytes;
 
 /// The architecture-specific prelude.
 /// Any code that needs to interact with the architecture-specific parts of the kernel should wildcard-import this module.
 /// Note that it is essential that these re-exports are limited to those that are really needed.
 pub mod prelude {
     // When re-exporting a "protocol", we should re-export the trait itself so that calling code can use it's items.
-    pub use super::memory::VirtualMemoryManager;
-    pub use crate::memory::VirtualMemoryManagerProtocol;
+    pub use super::memory::{AddressSpace, MemoryModel, VirtualMemoryManager};
+    pub use crate::memory::{AddressSpaceProtocol, VirtualMemoryManagerProtocol};
 }
@@ -0,0 +1,97 @@
+use core::arch::{asm, global_asm, x86_64::__cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Whether SMAP is enabled, in which case the kernel has to set `RFLAGS.AC` (with `stac`) to touch user memory.
+/// `stac` and `clac` don't exist on processors without SMAP.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Copies `rdx` bytes from `rsi` to `rdi`, and returns the number of bytes that were left uncopied.
+//
+// If either side faults, the page fault handler resumes at `copy_user_bytes_end` (see `fixup_user_access_fault`),
+// with RCX still holding the number of bytes that were left.
+global_asm!(
+    ".global copy_user_bytes_raw",
+    ".global copy_user_bytes_access",
+    ".global copy_user_bytes_end",
+    "copy_user_bytes_raw:",
+    "    mov rcx, rdx",
+    "copy_user_bytes_access:",
+    "    rep movsb",
+    "copy_user_bytes_end:",
+    "    mov rax, rcx",
+    "    ret",
+);
+
+extern "sysv64" {
+    fn copy_user_bytes_raw(destination: *mut u8, source: *const u8, len: usize) -> usize;
+
+    // Labels inside `copy_user_bytes_raw`, only used for their addresses.
+    fn copy_user_bytes_access();
+    fn copy_user_bytes_end();
+}
+
+/// Enables the processor features that keep the kernel and user mode apart, where they are supported:
+/// * SMEP, which stops the kernel from executing user pages.
+/// * SMAP, which stops the kernel from accessing user pages, except through [`copy_user_bytes`].
+/// * UMIP, which stops user mode from reading the descriptor table registers (SGDT, SIDT and friends).
+pub fn init() {
+    // SAFETY: CPUID is always available in long mode.
+    let features = unsafe { __cpuid_count(7, 0) };
+
+    // Bit 7 of EBX is the "SMEP" feature flag, bit 20 of EBX is "SMAP", and bit 2 of ECX is "UMIP".
+    let mut flags = Cr4Flags::empty();
+    if features.ebx & (1 << 7) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.ebx & (1 << 20) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if features.ecx & (1 << 2) != 0 {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+
+    unsafe {
+        // SAFETY: Nothing the kernel runs is mapped as a user page, and nothing touches user memory other than
+        // through `copy_user_bytes`.
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+    SMAP_ENABLED.store(flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION), Ordering::Relaxed);
+    log::info!("Enabled user mode protections: {:?}", flags);
+}
+
+/// Copies `len` bytes from `source` to `destination`, either of which may be in user space.
+///
+/// Returns the number of bytes that were left uncopied because of a page fault, which is 0 if the whole copy worked.
+///
+/// # Safety
+/// The kernel side of the copy must be valid for `len` bytes. The user side must lie inside user space; if it isn't
+/// mapped (or is read-only, when writing), the copy stops instead of panicking.
+pub unsafe fn copy_user_bytes(destination: *mut u8, source: *const u8, len: usize) -> usize {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        asm!("stac", options(nostack));
+    }
+    let left = copy_user_bytes_raw(destination, source, len);
+    if smap {
+        asm!("clac", options(nostack));
+    }
+    left
+}
+
+/// Makes a page fault in [`copy_user_bytes`] end the copy, instead of being fatal.
+///
+/// Returns `false` if the fault happened anywhere else.
+pub fn fixup_user_access_fault(stack_frame: &mut InterruptStackFrame) -> bool {
+    if stack_frame.instruction_pointer.as_u64() != copy_user_bytes_access as usize as u64 {
+        return false;
+    }
+    unsafe {
+        // SAFETY: `copy_user_bytes_end` is the instruction after the copy, which only needs RCX (the bytes left).
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = x86_64::VirtAddr::new(copy_user_bytes_end as usize as u64);
+        });
+    }
+    true
+}
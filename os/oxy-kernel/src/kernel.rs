@@ -21,7 +21,25 @@ impl Kernel {
     pub fn run(mut self) -> ! {
         log::info!("Oxygen OS Kernel started!");
         log::debug!("Debug logging enabled.");
+        self.log_memory_stats();
+        #[cfg(debug_assertions)]
+        crate::memory::self_test::run(&self.kmm);
 
         todo!();
     }
+
+    fn log_memory_stats(&self) {
+        let stats = self.kmm.stats();
+        log::info!(
+            "Frames: {} total, {} free ({} usable, {} bootloader, {} firmware, {} reclaimed)",
+            stats.frames.total, stats.frames.free, stats.frames.usable, stats.frames.bootloader, stats.frames.firmware,
+            stats.frames.reclaimed);
+        log::info!(
+            "Heap: {} KiB used, {} KiB free, {} KiB peak (limit {} KiB)",
+            stats.heap.used / 1024, stats.heap.free / 1024, stats.heap.peak / 1024, stats.heap.limit / 1024);
+        log::info!("Page tables: {} frames", stats.page_table_frames);
+        for space in stats.spaces.iter() {
+            log::info!("Mapped in {} space: {} pages", space.name, space.mapped_pages);
+        }
+    }
 }
@@ -6,6 +6,8 @@
 #![feature(step_trait)]
 #![feature(const_trait_impl)]
 #![feature(effects)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![cfg_attr(target_arch = "x86_64", feature(abi_x86_interrupt))]
 
 extern crate alloc;
@@ -0,0 +1,52 @@
+use crate::arch::prelude::*;
+use crate::memory::{Error, Mapping, Page, PageFlags};
+
+/// Provides a protocol for architecture-dependent address spaces.
+///
+/// An address space has its own user-mode mappings in [`VirtualMemoryManagerProtocol::USER_SPACE`], and shares the
+/// kernel's mappings in [`VirtualMemoryManagerProtocol::KERNEL_SPACE`] with every other address space.
+/// See [`VirtualMemoryManagerProtocol`] for more about protocols.
+pub trait AddressSpaceProtocol: Sized {
+    /// Creates a new, empty address space that shares the kernel's mappings.
+    fn try_new(vmm: &mut VirtualMemoryManager) -> Result<Self, Error>;
+
+    /// Attempts to map a user page in this address space to an arbitrary frame.
+    ///
+    /// Fails with [`Error::OutsideUserSpace`] if the page isn't in [`VirtualMemoryManagerProtocol::USER_SPACE`].
+    /// The [`PageFlags::USER`] flag is always added.
+    fn try_map(&mut self, vmm: &mut VirtualMemoryManager, page: Page, flags: PageFlags) -> Result<<VirtualMemoryManager as VirtualMemoryManagerProtocol>::FlushPromise, Error>;
+
+    /// Attempts to unmap a user page in this address space, returning its frame to the frame allocator.
+    fn try_unmap(&mut self, vmm: &mut VirtualMemoryManager, page: Page) -> Result<<VirtualMemoryManager as VirtualMemoryManagerProtocol>::FlushPromise, Error>;
+
+    /// Creates a copy of this address space whose user pages share their frames with this one.
+    ///
+    /// Writable pages become copy-on-write in both address spaces: they are made read-only, and whichever address
+    /// space writes to one first gets a private copy of its frame. Read-only pages stay shared for as long as both
+    /// address spaces map them.
+    fn try_clone(&mut self, vmm: &mut VirtualMemoryManager) -> Result<Self, Error>;
+
+    /// Resolves a write fault on a copy-on-write page in this address space, which doesn't have to be active.
+    ///
+    /// See [`VirtualMemoryManagerProtocol::try_resolve_copy_on_write`].
+    fn try_resolve_copy_on_write(&mut self, vmm: &mut VirtualMemoryManager, page: Page) -> Result<<VirtualMemoryManager as VirtualMemoryManagerProtocol>::FlushPromise, Error>;
+
+    /// Visits every present mapping in this address space, including the kernel mappings it shares, in address order.
+    ///
+    /// See [`VirtualMemoryManagerProtocol::walk_mappings`].
+    fn walk_mappings(&self, vmm: &VirtualMemoryManager, visit: &mut dyn FnMut(Mapping));
+
+    /// Checks if this is the address space the current CPU is running in.
+    fn is_active(&self) -> bool;
+
+    /// Switches the current CPU to this address space.
+    ///
+    /// # Safety
+    /// Anything the currently running code relies on in user space will no longer be mapped.
+    unsafe fn activate(&self);
+
+    /// Destroys the address space, returning its user pages and page tables to the frame allocator.
+    ///
+    /// Fails if the address space is active on the current CPU.
+    fn destroy(self, vmm: &mut VirtualMemoryManager) -> Result<(), Error>;
+}
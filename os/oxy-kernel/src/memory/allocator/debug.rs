@@ -0,0 +1,235 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use spinning_top::Spinlock;
+use super::{KernelAllocator, ALLOCATOR};
+
+#[global_allocator]
+static DEBUG_ALLOCATOR: DebugAllocator = DebugAllocator { inner: &ALLOCATOR };
+
+/// Every allocation that hasn't been freed yet.
+static LIVE: Spinlock<LiveAllocations> = Spinlock::new(LiveAllocations {
+    head: null_mut(),
+    count: 0,
+    bytes: 0,
+    next_sequence: 0,
+});
+
+/// The number of bytes on either side of an allocation that must not be touched.
+const RED_ZONE_SIZE: usize = 16;
+
+/// What the red zones are filled with.
+const RED_ZONE_BYTE: u8 = 0xfd;
+
+/// What freed memory is filled with, so that use after free reads obviously bad values.
+const FREED_BYTE: u8 = 0xdd;
+
+/// The number of bytes left unused at the start of each block.
+///
+/// Once a block is freed, the inner allocator keeps its free list in the first bytes of it (a pointer for the slabs,
+/// a size and a pointer for the heap). The header has to stay clear of them for [`FREED_MAGIC`] to survive the free.
+const FREE_LIST_SIZE: usize = 2 * core::mem::size_of::<usize>();
+
+/// The number of return addresses recorded for each allocation.
+const CALLER_DEPTH: usize = 4;
+
+/// Marks the header of a live allocation.
+const LIVE_MAGIC: u64 = 0xa110_ca7e_d0d0_cafe;
+
+/// Replaces [`LIVE_MAGIC`] when an allocation is freed, so that freeing it again is caught.
+const FREED_MAGIC: u64 = 0xf4ee_d0d0_dead_beef;
+
+/// Wraps the kernel allocator to catch heap corruption as close to its cause as possible.
+///
+/// Each allocation is laid out as room for the inner allocator's free list, a header, a red zone, the memory that was
+/// asked for, and another red zone:
+///
+/// ```text
+/// | free list | padding | Header | red zone | allocation | red zone |
+/// ```
+///
+/// The red zones are checked when the allocation is freed (or on demand with [`check_allocations`]), so writing past
+/// either end of an allocation panics with the call site that made it. Freed memory is poisoned, and every live
+/// allocation is tracked in an intrusive list, so leaks can be found with [`dump_allocations`] without the tracking
+/// itself allocating.
+struct DebugAllocator {
+    inner: &'static KernelAllocator,
+}
+
+/// Describes an allocation. Lives directly in front of the allocation's first red zone.
+struct Header {
+    magic: u64,
+    size: usize,
+
+    /// The order the allocation was made in, see [`allocation_sequence`].
+    sequence: u64,
+
+    /// The return addresses of the allocation's call stack, innermost first. Unused entries are zero.
+    callers: [usize; CALLER_DEPTH],
+
+    previous: *mut Header,
+    next: *mut Header,
+}
+
+struct LiveAllocations {
+    head: *mut Header,
+    count: usize,
+    bytes: usize,
+    next_sequence: u64,
+}
+
+// SAFETY: The headers are only touched with the lock held (or by whoever owns the allocation).
+unsafe impl Send for LiveAllocations {}
+
+impl DebugAllocator {
+    /// Gets the layout of the whole block that holds an allocation of `layout`, and the offset of the allocation in it.
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(core::mem::align_of::<Header>());
+        let offset = (FREE_LIST_SIZE + core::mem::size_of::<Header>() + RED_ZONE_SIZE).next_multiple_of(align);
+        let outer = Layout::from_size_align(offset + layout.size() + RED_ZONE_SIZE, align).unwrap();
+        (outer, offset)
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, offset) = Self::outer_layout(layout);
+        let block = self.inner.alloc(outer);
+        if block.is_null() {
+            return null_mut();
+        }
+
+        let ptr = block.add(offset);
+        ptr.sub(RED_ZONE_SIZE).write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+
+        let mut callers = [0; CALLER_DEPTH];
+        crate::arch::return_addresses(0, &mut callers);
+
+        let header = header_of(ptr);
+        let mut live = LIVE.lock();
+        header.write(Header {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            sequence: live.next_sequence,
+            callers,
+            previous: null_mut(),
+            next: live.head,
+        });
+        if let Some(head) = live.head.as_mut() {
+            head.previous = header;
+        }
+        live.head = header;
+        live.count += 1;
+        live.bytes += layout.size();
+        live.next_sequence += 1;
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = &mut *header_of(ptr);
+        match header.magic {
+            LIVE_MAGIC => {},
+            FREED_MAGIC => panic!("double free of {:p} ({} bytes)", ptr, layout.size()),
+            _ => panic!("freeing {:p}, which was not allocated, or whose header has been overwritten", ptr),
+        }
+        if header.size != layout.size() {
+            log_allocation(header, ptr);
+            panic!("freeing {:p} as {} bytes, but it was allocated as {} bytes", ptr, layout.size(), header.size);
+        }
+        if !red_zones_intact(ptr, header.size) {
+            log_allocation(header, ptr);
+            panic!("heap corruption: the red zones around {:p} ({} bytes) have been overwritten", ptr, header.size);
+        }
+
+        {
+            let mut live = LIVE.lock();
+            match header.previous.as_mut() {
+                Some(previous) => previous.next = header.next,
+                None => live.head = header.next,
+            }
+            if let Some(next) = header.next.as_mut() {
+                next.previous = header.previous;
+            }
+            live.count -= 1;
+            live.bytes -= header.size;
+        }
+        header.magic = FREED_MAGIC;
+
+        let (outer, offset) = Self::outer_layout(layout);
+        ptr.sub(RED_ZONE_SIZE).write_bytes(FREED_BYTE, layout.size() + 2 * RED_ZONE_SIZE);
+        self.inner.dealloc(ptr.sub(offset), outer);
+    }
+}
+
+/// Gets the sequence number the next allocation will get.
+///
+/// Taking this before running some code, and passing it to [`dump_allocations`] afterwards, shows what that code
+/// allocated and didn't free.
+pub fn allocation_sequence() -> u64 {
+    LIVE.lock().next_sequence
+}
+
+/// Logs every live allocation made since `since` (see [`allocation_sequence`]), newest first, followed by the
+/// totals for all live allocations.
+pub fn dump_allocations(since: u64) {
+    let live = LIVE.lock();
+    let mut header = live.head;
+    while let Some(current) = unsafe { header.as_ref() } {
+        if current.sequence < since {
+            // The list is newest first, so everything from here on is older.
+            break;
+        }
+        log_allocation(current, unsafe { allocation_of(header) });
+        header = current.next;
+    }
+    log::info!("{} live allocations, {} bytes", live.count, live.bytes);
+}
+
+/// Checks the red zones of every live allocation, logging any that have been overwritten.
+///
+/// Returns `true` if every red zone is intact.
+pub fn check_allocations() -> bool {
+    let live = LIVE.lock();
+    let mut intact = true;
+    let mut header = live.head;
+    while let Some(current) = unsafe { header.as_ref() } {
+        let ptr = unsafe { allocation_of(header) };
+        if current.magic != LIVE_MAGIC || !unsafe { red_zones_intact(ptr, current.size) } {
+            log::error!("Heap corruption around {:p}:", ptr);
+            log_allocation(current, ptr);
+            intact = false;
+        }
+        header = current.next;
+    }
+    intact
+}
+
+fn log_allocation(header: &Header, ptr: *mut u8) {
+    log::info!("  #{} {:p}: {} bytes", header.sequence, ptr, header.size);
+    for address in header.callers.iter().take_while(|&&address| address != 0) {
+        log::info!("      from {:#x}", address);
+    }
+}
+
+/// Gets the header of the allocation at `ptr`.
+///
+/// # Safety
+/// `ptr` must have been returned by [`DebugAllocator::alloc`].
+unsafe fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.sub(RED_ZONE_SIZE + core::mem::size_of::<Header>()).cast()
+}
+
+/// The inverse of [`header_of`].
+unsafe fn allocation_of(header: *mut Header) -> *mut u8 {
+    header.cast::<u8>().add(core::mem::size_of::<Header>() + RED_ZONE_SIZE)
+}
+
+/// Checks that the red zones on either side of the `size` bytes at `ptr` still hold [`RED_ZONE_BYTE`].
+///
+/// # Safety
+/// `ptr` must have been returned by [`DebugAllocator::alloc`] for an allocation of `size` bytes.
+unsafe fn red_zones_intact(ptr: *mut u8, size: usize) -> bool {
+    let front = core::slice::from_raw_parts(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE);
+    let back = core::slice::from_raw_parts(ptr.add(size), RED_ZONE_SIZE);
+    front.iter().chain(back).all(|&byte| byte == RED_ZONE_BYTE)
+}
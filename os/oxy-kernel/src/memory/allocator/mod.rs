@@ -0,0 +1,192 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use conquer_once::spin::OnceCell;
+use linked_list_allocator::Heap;
+use spinning_top::Spinlock;
+use crate::memory;
+use crate::arch::prelude::*;
+use crate::memory::{CpuLock, HeapStats, Page, PageFlags, VirtualAddress, FlushPromise};
+
+mod slab;
+mod oom;
+#[cfg(feature = "heap-debug")]
+mod debug;
+
+pub use slab::{ObjectCache, Slab};
+#[cfg(feature = "heap-debug")]
+pub use debug::{allocation_sequence, check_allocations, dump_allocations};
+
+/// With the `heap-debug` feature, this is wrapped by the debug allocator instead of being used directly.
+#[cfg_attr(not(feature = "heap-debug"), global_allocator)]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+const INITIAL_HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+
+/// The smallest amount the heap grows by, so that a run of small allocations doesn't map one page at a time.
+const MIN_GROWTH: usize = 64 * 1024; // 64 KiB
+
+/// The default upper bound on the size of the heap.
+pub const DEFAULT_HEAP_LIMIT: usize = 256 * 1024 * 1024; // 256 MiB
+
+/// The block sizes of the general-purpose slabs. Anything larger is allocated in whole pages from the heap.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The kernel's global allocator.
+///
+/// Small allocations are served from a slab for the smallest size class that fits, so they never have to search
+/// (and fragment) the heap. Larger allocations are rounded up to whole pages and taken directly from the heap.
+struct KernelAllocator {
+    slabs: [Spinlock<Slab>; SIZE_CLASSES.len()],
+    heap: GrowableHeap,
+}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        KernelAllocator {
+            slabs: [
+                Spinlock::new(Slab::new(SIZE_CLASSES[0])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[1])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[2])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[3])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[4])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[5])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[6])),
+                Spinlock::new(Slab::new(SIZE_CLASSES[7])),
+            ],
+            heap: GrowableHeap::new(),
+        }
+    }
+
+    /// Finds the slab that serves allocations of `layout`, if it is small enough for one.
+    fn slab_for(&self, layout: Layout) -> Option<&Spinlock<Slab>> {
+        let size = layout.size().max(layout.align());
+        let class = SIZE_CLASSES.iter().position(|&class| class >= size)?;
+        Some(&self.slabs[class])
+    }
+
+    /// Rounds a layout that is too large for the slabs up to whole, page-aligned pages.
+    fn page_layout(layout: Layout) -> Layout {
+        Layout::from_size_align(
+            layout.size().next_multiple_of(VirtualMemoryManager::PAGE_SIZE),
+            layout.align().max(VirtualMemoryManager::PAGE_SIZE),
+        ).unwrap()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match self.slab_for(layout) {
+            Some(slab) => slab.lock().allocate(),
+            None => self.heap.allocate(Self::page_layout(layout)),
+        };
+        ptr.map_or(null_mut(), |p| p.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new_unchecked(ptr);
+        match self.slab_for(layout) {
+            Some(slab) => slab.lock().deallocate(ptr),
+            None => self.heap.deallocate(ptr, Self::page_layout(layout)),
+        }
+    }
+}
+
+/// A linked-list heap that lives in [`VirtualMemoryManagerProtocol::KERNEL_HEAP_SPACE`] and maps more pages at its
+/// end whenever an allocation can't be satisfied, up to a configurable limit.
+struct GrowableHeap {
+    heap: Spinlock<Heap>,
+    vmm: OnceCell<&'static CpuLock<VirtualMemoryManager>>,
+    limit: AtomicUsize,
+
+    /// The most bytes that have been in use at once.
+    peak: AtomicUsize,
+}
+
+impl GrowableHeap {
+    const fn new() -> Self {
+        GrowableHeap {
+            heap: Spinlock::new(Heap::empty()),
+            vmm: OnceCell::uninit(),
+            limit: AtomicUsize::new(DEFAULT_HEAP_LIMIT),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let mut heap = self.heap.lock();
+        let ptr = match heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr,
+            Err(_) => {
+                self.grow(&mut heap, layout).ok()?;
+                heap.allocate_first_fit(layout).ok()?
+            },
+        };
+        self.peak.fetch_max(heap.used(), Ordering::Relaxed);
+        Some(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.lock().deallocate(ptr, layout);
+    }
+
+    /// Grows the heap so that an allocation of `layout` will fit in the new space at its end.
+    fn grow(&self, heap: &mut Heap, layout: Layout) -> Result<(), memory::Error> {
+        // Another CPU holding the VMM will let go of it, so we wait. But if this CPU holds it, the VMM is the one
+        // allocating, and waiting would never end, so we can't grow.
+        let vmm = self.vmm.get().ok_or(memory::Error::Other("the heap has not been initialized"))?;
+        let mut vmm = vmm.lock_unless_held().ok_or(memory::Error::Other("the VMM is locked by this CPU"))?;
+
+        let growth = (layout.size() + layout.align())
+            .max(MIN_GROWTH)
+            .next_multiple_of(VirtualMemoryManager::PAGE_SIZE);
+        let growth = growth.min(self.limit.load(Ordering::Relaxed).saturating_sub(heap.size()));
+        let heap_end = VirtualAddress::new(heap.top() as usize);
+        if growth < layout.size() || heap_end + growth > VirtualMemoryManager::KERNEL_HEAP_SPACE.end {
+            return Err(memory::Error::VirtualSpaceExhausted);
+        }
+
+        let pages = Page::containing(heap_end)..Page::containing(heap_end + growth);
+        vmm.try_map_range(pages, PageFlags::WRITABLE | PageFlags::HUGE)?.flush();
+        unsafe {
+            // SAFETY: We just mapped the space directly after the end of the heap.
+            heap.extend(growth);
+        }
+        Ok(())
+    }
+}
+
+/// Maps the initial heap at `start`, which must be page-aligned and inside
+/// [`VirtualMemoryManagerProtocol::KERNEL_HEAP_SPACE`], and sets up the allocator to use it.
+pub fn initialize(vmm: &'static CpuLock<VirtualMemoryManager>, start: VirtualAddress) -> Result<(), memory::Error> {
+    // Map the Kernel Heap
+    vmm.lock().try_map_range(Page::range_covering(start..start + INITIAL_HEAP_SIZE), PageFlags::WRITABLE | PageFlags::HUGE)?.flush();
+
+    // Initialize the allocator
+    unsafe {
+        // SAFETY: We just mapped the heap space, so it is safe to initialize the allocator.
+        ALLOCATOR.heap.heap.lock().init(start.value() as *mut u8, INITIAL_HEAP_SIZE);
+    }
+    ALLOCATOR.heap.vmm.init_once(|| vmm);
+
+    Ok(())
+}
+
+/// Gets statistics about the heap.
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.heap.heap.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+        peak: ALLOCATOR.heap.peak.load(Ordering::Relaxed),
+        limit: ALLOCATOR.heap.limit.load(Ordering::Relaxed),
+    }
+}
+
+/// Sets the largest size, in bytes, that the heap may grow to.
+///
+/// Lowering the limit below the current size of the heap stops it from growing further, but doesn't shrink it.
+pub fn set_heap_limit(limit: usize) {
+    ALLOCATOR.heap.limit.store(limit, Ordering::Relaxed);
+}
@@ -0,0 +1,39 @@
+use core::alloc::Layout;
+use crate::arch::prelude::*;
+use crate::memory::kmm::VMM;
+use super::stats;
+
+/// The number of return addresses logged when an allocation fails.
+const BACKTRACE_DEPTH: usize = 16;
+
+/// Called when an infallible allocation fails. Logs what we know about the state of memory before giving up.
+///
+/// Code that can cope with running out of memory should use the helpers in [`crate::memory::fallible`] instead, which
+/// return [`crate::memory::Error::OutOfMemory`] rather than ending up here.
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    // Nothing here may allocate. The allocator's locks are all released by the time we get here.
+    log::error!("Out of memory allocating {} bytes (aligned to {})", layout.size(), layout.align());
+
+    let heap = stats();
+    log::error!(
+        "Heap: {} KiB used, {} KiB free, {} KiB in total, {} KiB peak (limit {} KiB)",
+        heap.used / 1024, heap.free / 1024, heap.size / 1024, heap.peak / 1024, heap.limit / 1024);
+
+    // The VMM may be the one allocating, so don't wait for its lock.
+    match VMM.get().and_then(|vmm| vmm.try_lock()) {
+        Some(vmm) => {
+            let frames = vmm.frame_stats();
+            log::error!("Frames: {} free of {}", frames.free, frames.total);
+        },
+        None => log::error!("Frames: unknown, the VMM is busy"),
+    }
+
+    let mut callers = [0; BACKTRACE_DEPTH];
+    let count = crate::arch::return_addresses(0, &mut callers);
+    for address in &callers[..count] {
+        log::error!("    from {:#x}", address);
+    }
+
+    panic!("out of memory");
+}
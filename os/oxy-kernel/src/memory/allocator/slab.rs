@@ -0,0 +1,148 @@
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use spinning_top::Spinlock;
+use crate::arch::prelude::*;
+use crate::memory::allocator::ALLOCATOR;
+
+/// A free block in a slab, which stores the link to the next free block in its own memory.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A free list of equally-sized blocks, carved out of page-sized slabs taken from the heap.
+///
+/// Allocating and freeing are just a push or pop on the free list, so allocations that fit in a slab never have to
+/// search the heap. Slabs are never returned to the heap; freed blocks stay on the free list for reuse.
+pub struct Slab {
+    block_size: usize,
+    free: Option<NonNull<FreeBlock>>,
+    allocated: usize,
+    slabs: usize,
+}
+
+// SAFETY: The free list only points into slab memory, which is owned by the slab and not shared with anything else.
+unsafe impl Send for Slab {}
+
+impl Slab {
+    /// Creates an empty slab of `block_size` blocks.
+    ///
+    /// `block_size` must be a power of two at least as large as a pointer, and no larger than a page, so that every
+    /// block in a page-aligned slab is aligned to its own size.
+    pub const fn new(block_size: usize) -> Self {
+        assert!(block_size.is_power_of_two());
+        assert!(block_size >= core::mem::size_of::<FreeBlock>());
+        assert!(block_size <= VirtualMemoryManager::PAGE_SIZE);
+        Slab {
+            block_size,
+            free: None,
+            allocated: 0,
+            slabs: 0,
+        }
+    }
+
+    /// Gets the number of blocks currently allocated from this slab.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        if self.free.is_none() {
+            self.refill()?;
+        }
+
+        let block = self.free?;
+        unsafe {
+            // SAFETY: Blocks on the free list are valid, unused, and hold a `FreeBlock`.
+            self.free = block.as_ref().next;
+        }
+        self.allocated += 1;
+        Some(block.cast())
+    }
+
+    /// Returns a block to the slab.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`Slab::allocate`] on this slab, and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let mut block = ptr.cast::<FreeBlock>();
+        block.as_mut().next = self.free;
+        self.free = Some(block);
+        self.allocated -= 1;
+    }
+
+    /// Takes a new page-sized slab from the heap and pushes all of its blocks on to the free list.
+    fn refill(&mut self) -> Option<()> {
+        let layout = Layout::from_size_align(VirtualMemoryManager::PAGE_SIZE, VirtualMemoryManager::PAGE_SIZE).unwrap();
+        let slab = ALLOCATOR.heap.allocate(layout)?;
+        for offset in (0..VirtualMemoryManager::PAGE_SIZE).step_by(self.block_size).rev() {
+            unsafe {
+                // SAFETY: The slab is a fresh, page-sized allocation, and every block lies entirely within it.
+                let mut block = NonNull::new_unchecked(slab.as_ptr().add(offset)).cast::<FreeBlock>();
+                block.as_mut().next = self.free;
+                self.free = Some(block);
+            }
+        }
+        self.slabs += 1;
+        Some(())
+    }
+}
+
+/// A named cache of objects of a single type.
+///
+/// Kernel objects that are allocated and freed often should get their own cache, which keeps them packed together
+/// in their own slabs rather than mixed in with the general size classes, and makes it easy to see how many exist.
+pub struct ObjectCache<T> {
+    name: &'static str,
+    slab: Spinlock<Slab>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    const BLOCK_SIZE: usize = {
+        let size = core::mem::size_of::<T>();
+        let align = core::mem::align_of::<T>();
+        let size = if size > align { size } else { align };
+        let size = if size > core::mem::size_of::<FreeBlock>() { size } else { core::mem::size_of::<FreeBlock>() };
+        size.next_power_of_two()
+    };
+
+    pub const fn new(name: &'static str) -> Self {
+        ObjectCache {
+            name,
+            slab: Spinlock::new(Slab::new(Self::BLOCK_SIZE)),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets the number of objects currently allocated from this cache.
+    pub fn allocated(&self) -> usize {
+        self.slab.lock().allocated()
+    }
+
+    /// Moves `value` into a new object from the cache, returning `None` if the heap is exhausted.
+    pub fn allocate(&self, value: T) -> Option<NonNull<T>> {
+        let object = self.slab.lock().allocate()?.cast::<T>();
+        unsafe {
+            // SAFETY: The block is unused, and large and aligned enough to hold a `T`.
+            object.as_ptr().write(value);
+        }
+        Some(object)
+    }
+
+    /// Drops an object and returns its memory to the cache.
+    ///
+    /// # Safety
+    /// `object` must have been returned by [`ObjectCache::allocate`] on this cache, and must not be used afterwards.
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        object.as_ptr().drop_in_place();
+        self.slab.lock().deallocate(object.cast());
+    }
+}
+
+// SAFETY: Objects are handed out by pointer; the cache itself only holds unused memory behind a lock.
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
@@ -0,0 +1,88 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A spinlock that knows which CPU holds it.
+///
+/// Code that can run in the middle of anything, like the page fault handler, can't just wait for a lock: if the CPU it
+/// is running on already holds it, waiting would never end. Such code can use [`CpuLock::lock_unless_held`], which
+/// only gives up in that case, and waits for other CPUs as usual.
+pub struct CpuLock<T> {
+    /// The index of the CPU holding the lock, plus one, or 0 if no CPU holds it.
+    owner: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: The lock makes sure only one CPU can reach the value at a time.
+unsafe impl<T: Send> Sync for CpuLock<T> {}
+
+impl<T> CpuLock<T> {
+    pub const fn new(value: T) -> Self {
+        CpuLock {
+            owner: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits for the lock and takes it.
+    pub fn lock(&self) -> CpuLockGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Takes the lock if nothing holds it.
+    pub fn try_lock(&self) -> Option<CpuLockGuard<T>> {
+        self.owner
+            .compare_exchange(0, current_owner(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| CpuLockGuard { lock: self })
+    }
+
+    /// Waits for the lock and takes it, unless the current CPU already holds it.
+    pub fn lock_unless_held(&self) -> Option<CpuLockGuard<T>> {
+        let me = current_owner();
+        loop {
+            match self.owner.compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(CpuLockGuard { lock: self }),
+                Err(owner) if owner == me => return None,
+                Err(_) => spin_loop(),
+            }
+        }
+    }
+}
+
+/// Holds a [`CpuLock`] until it is dropped.
+pub struct CpuLockGuard<'a, T> {
+    lock: &'a CpuLock<T>,
+}
+
+impl<T> Deref for CpuLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for CpuLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We hold the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for CpuLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(0, Ordering::Release);
+    }
+}
+
+fn current_owner() -> u32 {
+    crate::arch::current_cpu() + 1
+}
@@ -0,0 +1,94 @@
+use core::iter::Step;
+use core::ops::Range;
+use crate::arch::prelude::*;
+use crate::memory::{Error, FlushPromise, Frame, Page, PageFlags, PhysicalAddress, VirtualAddress};
+use crate::memory::ranges::MMIO_RANGES;
+
+/// A buffer that devices can read and write directly: physically contiguous, and mapped into the kernel's MMIO space.
+///
+/// The kernel accesses the buffer at [`DmaBuffer::virtual_address`], and hands [`DmaBuffer::physical_address`] to the
+/// device. The buffer is zeroed when it is allocated, so nothing the kernel left in those frames leaks to the device.
+pub struct DmaBuffer {
+    pages: Range<Page>,
+    frames: Range<Frame>,
+}
+
+impl DmaBuffer {
+    /// The limit for devices that can only address 32 bits, like most PCI devices without 64-bit BARs.
+    pub const BELOW_4GIB: PhysicalAddress = PhysicalAddress::new(0x1_0000_0000);
+
+    /// The limit for legacy ISA DMA.
+    pub const BELOW_16MIB: PhysicalAddress = PhysicalAddress::new(0x100_0000);
+
+    /// Allocates a buffer of at least `size` bytes.
+    ///
+    /// # Parameters
+    /// * `align` - The physical alignment of the buffer, in bytes. Anything up to a page is always satisfied.
+    /// * `limit` - The buffer lies entirely below this physical address, like [`DmaBuffer::BELOW_4GIB`].
+    pub fn allocate(vmm: &mut VirtualMemoryManager, size: usize, align: usize, limit: PhysicalAddress) -> Result<Self, Error> {
+        let page_count = size.div_ceil(VirtualMemoryManager::PAGE_SIZE).max(1);
+        let first_frame = vmm.try_allocate_contiguous(page_count, align.div_ceil(VirtualMemoryManager::PAGE_SIZE).max(1), limit)?;
+        let frames = first_frame..Step::forward(first_frame, page_count);
+
+        let pages = match MMIO_RANGES.lock().allocate(page_count, 1) {
+            Ok(pages) => pages,
+            Err(e) => {
+                vmm.deallocate_contiguous(frames);
+                return Err(e);
+            },
+        };
+        let result = unsafe {
+            // SAFETY: We just allocated the frames, so nothing else is using them.
+            vmm.try_map_range_to(pages.clone(), first_frame, PageFlags::WRITABLE)
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                MMIO_RANGES.lock().deallocate(pages.start)?;
+                vmm.deallocate_contiguous(frames);
+                return Err(e);
+            },
+        }
+
+        let buffer = DmaBuffer { pages, frames };
+        unsafe {
+            // SAFETY: The buffer was just mapped writable, and nothing else has it yet.
+            buffer.as_mut_ptr().write_bytes(0, buffer.size());
+        }
+        Ok(buffer)
+    }
+
+    /// Gets the virtual address the kernel can access the buffer at.
+    pub fn virtual_address(&self) -> VirtualAddress {
+        self.pages.start.start_address()
+    }
+
+    /// Gets the physical address to hand to the device.
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.frames.start.start_address()
+    }
+
+    /// Gets the size of the buffer in bytes, which is the requested size rounded up to whole pages.
+    pub fn size(&self) -> usize {
+        Step::steps_between(&self.pages.start, &self.pages.end).unwrap_or(0) * VirtualMemoryManager::PAGE_SIZE
+    }
+
+    /// Gets a pointer to the start of the buffer.
+    ///
+    /// The device may write to the buffer at any time, so reads and writes should be volatile unless the driver knows
+    /// the device is done with it.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.virtual_address().value() as *mut u8
+    }
+
+    /// Unmaps the buffer and returns its frames to the frame allocator.
+    ///
+    /// The device must not be using the buffer anymore.
+    pub fn free(self, vmm: &mut VirtualMemoryManager) -> Result<(), Error> {
+        // The frames were mapped with `try_map_range_to`, so unmapping them doesn't free them.
+        vmm.try_unmap_range(self.pages.clone())?.flush();
+        MMIO_RANGES.lock().deallocate(self.pages.start)?;
+        vmm.deallocate_contiguous(self.frames);
+        Ok(())
+    }
+}
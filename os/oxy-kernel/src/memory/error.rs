@@ -1,18 +1,50 @@
 use thiserror::Error;
 use crate::memory::page::Frame;
+use crate::memory::VirtualAddress;
+
+pub use oxy_memory::NotAlignedError;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("page is already mapped to frame {0}")]
     PageAlreadyMapped(Frame),
 
+    #[error("page is not mapped")]
+    PageNotMapped,
+
     #[error("frame allocation failed")]
     FrameAllocationFailed,
 
+    #[error("page lies inside a huge page mapping")]
+    InsideHugePage,
+
+    #[error("pages cannot be both writable and executable")]
+    WritableAndExecutable,
+
+    #[error("address is outside of user space")]
+    OutsideUserSpace,
+
+    #[error("the address space is in use")]
+    AddressSpaceActive,
+
+    #[error("memory area overlaps an existing area")]
+    AreaOverlaps,
+
+    #[error("address is not in a reserved memory area")]
+    NotReserved,
+
+    #[error("access is not permitted by the memory area")]
+    AccessViolation,
+
+    #[error("no virtual address space left in the requested range")]
+    VirtualSpaceExhausted,
+
+    #[error("out of memory")]
+    OutOfMemory,
+
+    #[error("user memory at {0} could not be accessed")]
+    UserAccessFault(VirtualAddress),
+
     #[error("{0}")]
     Other(&'static str),
 }
-
-#[derive(Error, Debug, Eq, PartialEq)]
-#[error("address is not properly aligned")]
-pub struct NotAlignedError;
@@ -0,0 +1,50 @@
+//! Allocation helpers that return [`Error::OutOfMemory`] instead of calling the alloc error handler, so that
+//! subsystems which can work with less memory (or fail a single request) don't bring the whole kernel down.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::memory::Error;
+
+/// Moves `value` into a new box.
+pub fn try_box<T>(value: T) -> Result<Box<T>, Error> {
+    Box::try_new(value).map_err(|_| Error::OutOfMemory)
+}
+
+/// Creates a vector with room for at least `capacity` elements.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, Error> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity).map_err(|_| Error::OutOfMemory)?;
+    Ok(vec)
+}
+
+/// Fallible versions of the [`Vec`] methods that allocate.
+pub trait FallibleVec<T> {
+    /// Appends `value`, growing the vector if needed.
+    ///
+    /// On failure the vector is unchanged, and `value` is dropped.
+    fn try_push(&mut self, value: T) -> Result<(), Error>;
+
+    /// Clones and appends every element of `values`, growing the vector if needed.
+    ///
+    /// On failure the vector is unchanged.
+    fn try_extend_from_slice(&mut self, values: &[T]) -> Result<(), Error>
+    where
+        T: Clone;
+}
+
+impl<T> FallibleVec<T> for Vec<T> {
+    fn try_push(&mut self, value: T) -> Result<(), Error> {
+        self.try_reserve(1).map_err(|_| Error::OutOfMemory)?;
+        self.push(value);
+        Ok(())
+    }
+
+    fn try_extend_from_slice(&mut self, values: &[T]) -> Result<(), Error>
+    where
+        T: Clone,
+    {
+        self.try_reserve(values.len()).map_err(|_| Error::OutOfMemory)?;
+        self.extend_from_slice(values);
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+use crate::arch::prelude::*;
+
+pub type PhysicalFrameAllocator = oxy_memory::PhysicalFrameAllocator<MemoryModel>;
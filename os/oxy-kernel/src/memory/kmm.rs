@@ -1,24 +1,84 @@
+use conquer_once::spin::OnceCell;
+use spinning_top::Spinlock;
 use crate::arch::prelude::*;
-use crate::memory::{allocator, PhysicalAddress, VirtualAddress};
+use crate::memory::{allocator, CpuLock, CpuLockGuard, Error, KernelLayout, MemoryStats, Page, PhysicalAddress, SpaceStats, VirtualAddress, VirtualRangeAllocator, BOOT_RANGES, STACK_RANGES};
+use crate::memory::stats::KERNEL_SPACES;
+
+/// The kernel's virtual memory manager.
+///
+/// It lives in a static so that code which can't be handed a `KernelMemory`, like the heap allocator, can reach it.
+pub(super) static VMM: OnceCell<CpuLock<VirtualMemoryManager>> = OnceCell::uninit();
 
 /// Manages kernel memory.
 ///
 /// Among other direct features, holding an instance of this struct guarantees that the Kernel heap
 /// is initialized and ready to use.
 pub struct KernelMemory {
-    vmm: VirtualMemoryManager
+    vmm: &'static CpuLock<VirtualMemoryManager>
 }
 
 impl KernelMemory {
-    pub fn new(mut vmm: VirtualMemoryManager) -> Self {
-        allocator::initialize(&mut vmm).expect("Failed to initialize allocator");
+    /// Takes over the virtual memory manager and starts the heap, laid out as `layout` says.
+    pub fn new(vmm: VirtualMemoryManager, layout: &KernelLayout) -> Self {
+        VMM.init_once(|| CpuLock::new(vmm));
+        let vmm = VMM.get().unwrap();
+        allocator::initialize(vmm, layout.heap).expect("Failed to initialize allocator");
+
+        // Keep the range allocators below the starts the layout gave them.
+        reserve_below(&STACK_RANGES, VirtualMemoryManager::KERNEL_STACK_SPACE.start, layout.stacks);
+        reserve_below(&BOOT_RANGES, VirtualMemoryManager::KERNEL_BOOT_SPACE.start, layout.boot);
 
         KernelMemory {
             vmm
         }
     }
 
-    pub fn vmm(&self) -> &VirtualMemoryManager {
-        &self.vmm
+    /// Locks the virtual memory manager.
+    ///
+    /// The heap grows by mapping pages through the VMM, so it can't grow while this lock is held. Avoid allocating
+    /// while holding it.
+    pub fn vmm(&self) -> CpuLockGuard<VirtualMemoryManager> {
+        self.vmm.lock()
+    }
+
+    /// Creates a new address space that shares the kernel's mappings.
+    pub fn create_address_space(&self) -> Result<AddressSpace, Error> {
+        AddressSpace::try_new(&mut self.vmm())
+    }
+
+    /// Destroys an address space, returning its memory to the frame allocator.
+    pub fn destroy_address_space(&self, space: AddressSpace) -> Result<(), Error> {
+        space.destroy(&mut self.vmm())
+    }
+
+    /// Gathers statistics about how the kernel is using memory.
+    pub fn stats(&self) -> MemoryStats {
+        // The heap is read first, since it is locked while it grows, and growing takes the VMM lock.
+        let heap = allocator::stats();
+        let vmm = self.vmm();
+        MemoryStats {
+            frames: vmm.frame_stats(),
+            heap,
+            page_table_frames: vmm.page_table_frames(),
+            spaces: KERNEL_SPACES.map(|(name, range)| SpaceStats {
+                name,
+                mapped_pages: vmm.mapped_pages(range),
+            }),
+        }
+    }
+
+    /// Sets the largest size, in bytes, that the kernel heap may grow to.
+    ///
+    /// Defaults to [`allocator::DEFAULT_HEAP_LIMIT`].
+    pub fn set_heap_limit(&self, limit: usize) {
+        allocator::set_heap_limit(limit);
+    }
+}
+
+fn reserve_below(ranges: &Spinlock<VirtualRangeAllocator>, space_start: VirtualAddress, start: VirtualAddress) {
+    if start > space_start {
+        ranges.lock()
+            .reserve(Page::range_covering(space_start..start))
+            .expect("Failed to reserve the space below the kernel layout");
     }
 }
@@ -0,0 +1,53 @@
+use core::ops::Range;
+use crate::memory::VirtualAddress;
+
+/// Where the kernel starts using each of the parts of kernel space that can be moved around at boot.
+///
+/// Everything in a window below its start is kept out of use: it either holds the bootloader's own mappings, or is
+/// left empty so that the start is hard to guess (see [`LayoutRandomizer`]).
+#[derive(Debug, Clone)]
+pub struct KernelLayout {
+    /// The start of the heap, in [`crate::memory::VirtualMemoryManagerProtocol::KERNEL_HEAP_SPACE`].
+    pub heap: VirtualAddress,
+
+    /// The lowest address handed out for kernel stacks, in [`crate::memory::VirtualMemoryManagerProtocol::KERNEL_STACK_SPACE`].
+    pub stacks: VirtualAddress,
+
+    /// The lowest address handed out for boot-time mappings, in [`crate::memory::VirtualMemoryManagerProtocol::KERNEL_BOOT_SPACE`].
+    pub boot: VirtualAddress,
+
+    /// Where physical address zero is mapped, in [`crate::memory::VirtualMemoryManagerProtocol::KERNEL_PHYSICAL_SPACE`].
+    pub physical_memory: VirtualAddress,
+}
+
+/// Picks random places for the parts of a [`KernelLayout`].
+///
+/// This is a xorshift generator, which is nowhere near good enough for cryptography but is plenty for spreading a
+/// handful of offsets out, as long as the seed itself is unpredictable.
+pub struct LayoutRandomizer {
+    state: u64,
+}
+
+impl LayoutRandomizer {
+    pub fn new(seed: u64) -> Self {
+        // A zero state would only ever produce zeroes.
+        LayoutRandomizer { state: seed.max(1) }
+    }
+
+    /// Picks a random address in `window`, aligned to `align` bytes, that leaves at least `size` bytes before the
+    /// end of the window. Returns the start of the window if it isn't big enough.
+    pub fn place(&mut self, window: Range<VirtualAddress>, size: usize, align: usize) -> VirtualAddress {
+        let start = window.start.align_up(align);
+        let Some(slack) = window.end.value().checked_sub(start.value()).and_then(|space| space.checked_sub(size)) else {
+            return window.start;
+        };
+        start + (self.next() as usize % (slack / align + 1)) * align
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
@@ -0,0 +1,139 @@
+use core::fmt::Display;
+use core::iter::Step;
+use core::ops::Range;
+use crate::arch::prelude::*;
+use crate::memory::{Frame, KernelMemory, Page, PageFlags, PageSize};
+use crate::memory::kmm::VMM;
+use crate::memory::stats::KERNEL_SPACES;
+
+/// One present mapping, as found by walking the page tables.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub page: Page,
+    pub frame: Frame,
+    pub size: PageSize,
+    pub flags: PageFlags,
+}
+
+/// A run of mappings that are contiguous in both virtual and physical memory, and have the same size and flags.
+#[derive(Debug, Clone)]
+pub struct MappingRun {
+    pub pages: Range<Page>,
+    pub first_frame: Frame,
+    pub size: PageSize,
+    pub flags: PageFlags,
+
+    /// The part of the address space the run is in, like `"heap"` or `"user"`.
+    pub space: &'static str,
+}
+
+impl MappingRun {
+    fn new(mapping: Mapping) -> Self {
+        MappingRun {
+            pages: mapping.page..Step::forward(mapping.page, mapping.size.pages()),
+            first_frame: mapping.frame,
+            size: mapping.size,
+            flags: mapping.flags,
+            space: space_name(mapping.page),
+        }
+    }
+
+    /// Adds `mapping` to the end of the run, if it continues it.
+    fn extend(&mut self, mapping: &Mapping) -> bool {
+        let frames = Step::steps_between(&self.pages.start, &self.pages.end).unwrap_or(0);
+        let continues = mapping.page == self.pages.end
+            && mapping.frame == Step::forward(self.first_frame, frames)
+            && mapping.size == self.size
+            && mapping.flags == self.flags
+            && space_name(mapping.page) == self.space;
+        if continues {
+            self.pages.end = Step::forward(self.pages.end, mapping.size.pages());
+        }
+        continues
+    }
+}
+
+impl Display for MappingRun {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let count = Step::steps_between(&self.pages.start, &self.pages.end).unwrap_or(0) / self.size.pages();
+        let flag = |flag: PageFlags, c: char| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:<8} {:#018x}..{:#018x} -> {:#014x} {:>7} x {:?} r{}{}{}{} {:?}",
+            self.space,
+            self.pages.start.start_address().value(),
+            self.pages.end.start_address().value(),
+            self.first_frame.start_address().value(),
+            count,
+            self.size,
+            flag(PageFlags::WRITABLE, 'w'),
+            flag(PageFlags::EXECUTABLE, 'x'),
+            flag(PageFlags::USER, 'u'),
+            flag(PageFlags::GLOBAL, 'g'),
+            self.flags.cache_policy(),
+        )
+    }
+}
+
+/// Coalesces mappings, visited in address order, into [`MappingRun`]s.
+///
+/// Runs are passed on as soon as they end, so nothing is allocated, which keeps this usable from panic handlers.
+pub fn coalesce_mappings(walk: impl FnOnce(&mut dyn FnMut(Mapping)), mut emit: impl FnMut(&MappingRun)) {
+    let mut current: Option<MappingRun> = None;
+    walk(&mut |mapping| {
+        if current.as_mut().is_some_and(|run| run.extend(&mapping)) {
+            return;
+        }
+        if let Some(run) = current.replace(MappingRun::new(mapping)) {
+            emit(&run);
+        }
+    });
+    if let Some(run) = current {
+        emit(&run);
+    }
+}
+
+/// Logs the mappings visited by `walk`, coalesced into runs.
+fn log_mappings(title: &str, walk: impl FnOnce(&mut dyn FnMut(Mapping))) {
+    log::info!("{}:", title);
+    coalesce_mappings(walk, |run| log::info!("  {}", run));
+}
+
+/// Logs every mapping in the kernel's page tables, without waiting for the VMM lock.
+///
+/// Meant for panic handlers and debugging, where the lock may already be held by the code being debugged. Returns
+/// `false` (and logs nothing) if the lock is held or memory hasn't been initialized.
+pub fn try_dump_mappings() -> bool {
+    match VMM.get().and_then(|vmm| vmm.try_lock()) {
+        Some(vmm) => {
+            log_mappings("Kernel mappings", |visit| vmm.walk_mappings(visit));
+            true
+        },
+        None => false,
+    }
+}
+
+impl KernelMemory {
+    /// Logs every mapping in the kernel's page tables.
+    pub fn dump_mappings(&self) {
+        let vmm = self.vmm();
+        log_mappings("Kernel mappings", |visit| vmm.walk_mappings(visit));
+    }
+
+    /// Logs every mapping in an address space, including the kernel mappings it shares.
+    pub fn dump_address_space(&self, space: &AddressSpace) {
+        let vmm = self.vmm();
+        log_mappings("Address space mappings", |visit| space.walk_mappings(&vmm, visit));
+    }
+}
+
+/// Gets the name of the part of the address space `page` is in.
+fn space_name(page: Page) -> &'static str {
+    let address = page.start_address();
+    if VirtualMemoryManager::USER_SPACE.contains(&address) {
+        return "user";
+    }
+    KERNEL_SPACES.iter()
+        .find(|(_, range)| range.contains(&address))
+        .map_or("kernel", |(name, _)| name)
+}
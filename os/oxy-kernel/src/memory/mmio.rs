@@ -0,0 +1,98 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, Range};
+use core::ptr::NonNull;
+use crate::arch::prelude::*;
+use crate::memory::{CachePolicy, Error, FlushPromise, Frame, Page, PageFlags, PhysicalAddress, VirtualAddress};
+use crate::memory::ranges::MMIO_RANGES;
+
+/// A value that is only ever accessed with volatile reads and writes.
+///
+/// Device register blocks are described as `#[repr(C)]` structs of `Volatile` fields, and accessed through an
+/// [`MmioRegion`].
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        unsafe {
+            // SAFETY: The cell is always valid for reads, and volatile accesses can't be elided or merged.
+            self.0.get().read_volatile()
+        }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe {
+            // SAFETY: The cell is always valid for writes, and volatile accesses can't be elided or merged.
+            self.0.get().write_volatile(value)
+        }
+    }
+
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+}
+
+/// A typed mapping of a device's physical registers into the kernel's MMIO space.
+///
+/// The region dereferences to the register block `T`, whose fields should be [`Volatile`].
+pub struct MmioRegion<T> {
+    registers: NonNull<T>,
+    pages: Range<Page>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> MmioRegion<T> {
+    /// Maps the register block starting at physical `address` into the kernel's MMIO space.
+    ///
+    /// # Safety
+    /// `address` must point at a device register block (or other memory that is not owned by the kernel) that is laid
+    /// out as described by `T`.
+    pub unsafe fn map(vmm: &mut VirtualMemoryManager, address: PhysicalAddress, cache: CachePolicy) -> Result<Self, Error> {
+        let first_frame = Frame::containing(address);
+        let offset = address.value() - first_frame.start_address().value();
+        let page_count = (offset + core::mem::size_of::<T>()).div_ceil(VirtualMemoryManager::PAGE_SIZE);
+        let pages = MMIO_RANGES.lock().allocate(page_count, 1)?;
+
+        let result = vmm.try_map_range_to(pages.clone(), first_frame, PageFlags::WRITABLE.with_cache_policy(cache));
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                MMIO_RANGES.lock().deallocate(pages.start)?;
+                return Err(e);
+            },
+        }
+
+        Ok(MmioRegion {
+            registers: NonNull::new((pages.start.start_address() + offset).value() as *mut T).unwrap(),
+            pages,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Gets the virtual address at which the register block is mapped.
+    pub fn virtual_address(&self) -> VirtualAddress {
+        VirtualAddress::new(self.registers.as_ptr() as usize)
+    }
+
+    /// Unmaps the register block.
+    pub fn unmap(self, vmm: &mut VirtualMemoryManager) -> Result<(), Error> {
+        vmm.try_unmap_range(self.pages.clone())?.flush();
+        MMIO_RANGES.lock().deallocate(self.pages.start)?;
+        Ok(())
+    }
+}
+
+impl<T> Deref for MmioRegion<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe {
+            // SAFETY: The registers stay mapped for as long as the region exists.
+            self.registers.as_ref()
+        }
+    }
+}
+
+unsafe impl<T: Sync> Send for MmioRegion<T> {}
+unsafe impl<T: Sync> Sync for MmioRegion<T> {}
@@ -1,12 +1,42 @@
 mod page;
+mod address_space;
 mod vmm;
 mod kmm;
 mod address;
 mod error;
 mod allocator;
+mod frame_allocator;
+mod mmio;
+mod cpu_lock;
+mod stack;
+mod vma;
+mod stats;
+mod ranges;
+mod layout;
+mod mapping_dump;
+mod dma;
+pub mod fallible;
+mod user;
+#[cfg(debug_assertions)]
+pub mod self_test;
 
+pub use address_space::AddressSpaceProtocol;
+pub use allocator::ObjectCache;
+#[cfg(feature = "heap-debug")]
+pub use allocator::{allocation_sequence, check_allocations, dump_allocations};
 pub use kmm::KernelMemory;
+pub use cpu_lock::{CpuLock, CpuLockGuard};
+pub use layout::{KernelLayout, LayoutRandomizer};
+pub use mapping_dump::{coalesce_mappings, try_dump_mappings, Mapping, MappingRun};
+pub use dma::DmaBuffer;
 pub use error::{Error, NotAlignedError};
 pub use vmm::{FlushPromise, VirtualMemoryManagerProtocol};
-pub use page::{Frame, Page, PageWritability};
+pub use frame_allocator::PhysicalFrameAllocator;
+pub use mmio::{MmioRegion, Volatile};
+pub use ranges::{VirtualRangeAllocator, BOOT_RANGES, MMIO_RANGES, STACK_RANGES};
+pub use stack::KernelStack;
+pub use user::{copy_from_user, copy_to_user, UserData, UserPointer};
+pub use stats::{FrameStats, HeapStats, MemoryStats, SpaceStats};
+pub use vma::{resolve_copy_on_write, resolve_page_fault, FaultAccess, VirtualMemoryArea};
+pub use page::{CachePolicy, Frame, Page, PageFlags, PageSize};
 pub use address::{PhysicalAddress, VirtualAddress};
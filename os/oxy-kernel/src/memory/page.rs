@@ -1,97 +1,113 @@
-use core::fmt::Display;
-use core::iter::Step;
+use bitflags::bitflags;
 use crate::arch::prelude::*;
-use crate::memory::{NotAlignedError, PhysicalAddress, VirtualAddress};
 
-pub enum PageWritability {
-    ReadOnly,
-    ReadWrite,
-}
+bitflags! {
+    /// Architecture-neutral flags describing how a page may be accessed.
+    ///
+    /// Every mapped page is readable by the kernel; an empty set of flags means a read-only, non-executable kernel page
+    /// with normal (write-back) caching.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub struct PageFlags: u32 {
+        /// The page can be written to.
+        const WRITABLE = 1 << 0;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
-pub struct Page {
-    start: VirtualAddress
-}
+        /// Code in the page can be executed.
+        const EXECUTABLE = 1 << 1;
 
-impl Display for Page {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Page({} @ {})", self.number(), self.start)
-    }
-}
+        /// The page can be accessed from user mode.
+        const USER = 1 << 2;
 
-impl Page {
-    pub fn new(start: VirtualAddress) -> Result<Page, NotAlignedError> {
-        if !start.is_aligned(VirtualMemoryManager::PAGE_SIZE) {
-            return Err(NotAlignedError);
-        }
-        Ok(Page { start })
-    }
+        /// The mapping is the same in every address space, so it does not need to be flushed on an address space switch.
+        /// Kernel space mappings always get this flag.
+        const GLOBAL = 1 << 3;
 
-    pub fn with_number(number: usize) -> Page {
-        Page { start: VirtualAddress::new(number * VirtualMemoryManager::PAGE_SIZE) }
-    }
+        /// Writes go straight through to memory. See [`CachePolicy::WriteThrough`].
+        const WRITE_THROUGH = 1 << 4;
 
-    pub fn containing(address: VirtualAddress) -> Page {
-        let aligned = address.align_down(VirtualMemoryManager::PAGE_SIZE);
-        debug_assert!(aligned.is_aligned(VirtualMemoryManager::PAGE_SIZE));
-        Page { start: aligned }
-    }
+        /// Writes are buffered and combined. See [`CachePolicy::WriteCombining`].
+        const WRITE_COMBINING = 1 << 5;
 
-    pub fn start_address(&self) -> VirtualAddress {
-        self.start
-    }
+        /// Accesses are never cached. See [`CachePolicy::Uncached`].
+        const NO_CACHE = 1 << 6;
 
-    pub fn number(&self) -> usize {
-        self.start.value() / VirtualMemoryManager::PAGE_SIZE
+        /// Ranges may be mapped with larger pages wherever they are suitably aligned. Such a range can then only be
+        /// unmapped or protected a whole huge page at a time, so this is for ranges that are only ever released whole.
+        const HUGE = 1 << 7;
     }
 }
 
-impl Step for Page {
-    fn steps_between(start: &Self, end: &Self) -> Option<usize> {
-        Step::steps_between(&start.number(), &end.number())
-    }
-
-    fn forward_checked(start: Self, count: usize) -> Option<Self> {
-        let new_number = Step::forward_checked(start.number(), count)?;
-        Some(Page::with_number(new_number))
+impl PageFlags {
+    const CACHE_FLAGS: PageFlags = PageFlags::WRITE_THROUGH.union(PageFlags::WRITE_COMBINING).union(PageFlags::NO_CACHE);
+
+    /// Gets the cache policy described by these flags.
+    ///
+    /// If several cache flags are set, the most restrictive one wins.
+    pub fn cache_policy(&self) -> CachePolicy {
+        if self.contains(PageFlags::NO_CACHE) {
+            CachePolicy::Uncached
+        } else if self.contains(PageFlags::WRITE_COMBINING) {
+            CachePolicy::WriteCombining
+        } else if self.contains(PageFlags::WRITE_THROUGH) {
+            CachePolicy::WriteThrough
+        } else {
+            CachePolicy::WriteBack
+        }
     }
 
-    fn backward_checked(start: Self, count: usize) -> Option<Self> {
-        let new_number = Step::backward_checked(start.number(), count)?;
-        Some(Page::with_number(new_number))
+    /// Returns a copy of these flags with the cache flags replaced by those describing `cache`.
+    pub fn with_cache_policy(self, cache: CachePolicy) -> PageFlags {
+        let flags = self - PageFlags::CACHE_FLAGS;
+        match cache {
+            CachePolicy::WriteBack => flags,
+            CachePolicy::WriteThrough => flags | PageFlags::WRITE_THROUGH,
+            CachePolicy::WriteCombining => flags | PageFlags::WRITE_COMBINING,
+            CachePolicy::Uncached => flags | PageFlags::NO_CACHE,
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
-pub struct Frame {
-    start: PhysicalAddress
+/// Describes how the processor may cache accesses to a page.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// Reads and writes are fully cached. This is the right choice for normal RAM.
+    WriteBack,
+
+    /// Reads are cached, but writes always go straight through to memory.
+    WriteThrough,
+
+    /// Reads are uncached, and writes are buffered and combined. This is useful for framebuffers.
+    WriteCombining,
+
+    /// Reads and writes are never cached. This is the right choice for device registers.
+    Uncached,
 }
 
-impl Display for Frame {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Frame({} @ {})", self.number(), self.start)
-    }
+/// The sizes of page that can be mapped.
+///
+/// [`Page`] and [`Frame`] always describe standard-sized pages. A larger page is described by its first standard page
+/// (which must be aligned to the larger size) together with its `PageSize`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
 }
 
-impl Frame {
-    pub fn new(start: PhysicalAddress) -> Result<Frame, NotAlignedError> {
-        if !start.is_aligned(VirtualMemoryManager::PAGE_SIZE) {
-            return Err(NotAlignedError);
+impl PageSize {
+    /// Gets the size of the page in bytes.
+    pub const fn bytes(&self) -> usize {
+        match self {
+            PageSize::Size4KiB => 4 * 1024,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+            PageSize::Size1GiB => 1024 * 1024 * 1024,
         }
-        Ok(Frame { start })
-    }
-
-    pub fn containing(address: PhysicalAddress) -> Frame {
-        let aligned = address.align_down(VirtualMemoryManager::PAGE_SIZE);
-        debug_assert!(aligned.is_aligned(VirtualMemoryManager::PAGE_SIZE));
-        Frame { start: aligned }
     }
 
-    pub fn start_address(&self) -> PhysicalAddress {
-        self.start
-    }
-
-    pub fn number(&self) -> usize {
-        self.start.value() / VirtualMemoryManager::PAGE_SIZE
+    /// Gets the number of standard-sized pages that make up a page of this size.
+    pub const fn pages(&self) -> usize {
+        self.bytes() / VirtualMemoryManager::PAGE_SIZE
     }
 }
+
+pub type Page = oxy_memory::Page<MemoryModel>;
+pub type Frame = oxy_memory::Frame<MemoryModel>;
@@ -0,0 +1,91 @@
+use alloc::collections::BTreeMap;
+use core::iter::Step;
+use core::ops::Range;
+use spinning_top::Spinlock;
+use crate::arch::prelude::*;
+use crate::memory::{Error, Page, VirtualAddress};
+
+/// Hands out ranges of [`VirtualMemoryManagerProtocol::KERNEL_STACK_SPACE`] for kernel stacks.
+pub static STACK_RANGES: Spinlock<VirtualRangeAllocator> =
+    Spinlock::new(VirtualRangeAllocator::new(VirtualMemoryManager::KERNEL_STACK_SPACE));
+
+/// Hands out ranges of [`VirtualMemoryManagerProtocol::KERNEL_MMIO_SPACE`] for device mappings.
+pub static MMIO_RANGES: Spinlock<VirtualRangeAllocator> =
+    Spinlock::new(VirtualRangeAllocator::new(VirtualMemoryManager::KERNEL_MMIO_SPACE));
+
+/// Hands out ranges of [`VirtualMemoryManagerProtocol::KERNEL_BOOT_SPACE`] for boot-time and temporary mappings.
+pub static BOOT_RANGES: Spinlock<VirtualRangeAllocator> =
+    Spinlock::new(VirtualRangeAllocator::new(VirtualMemoryManager::KERNEL_BOOT_SPACE));
+
+/// Allocates non-overlapping ranges of pages from one part of the virtual address space.
+///
+/// Only the allocated ranges are tracked, ordered by their first page, and allocation takes the first gap between
+/// them that is large enough. This is meant for the kernel's long-lived regions (stacks, device mappings and the
+/// like), which are few and large, rather than for anything allocated at a high rate.
+pub struct VirtualRangeAllocator {
+    space: Range<VirtualAddress>,
+
+    /// The allocated ranges, as a map from the first page of each range to the page just past its end.
+    allocated: BTreeMap<Page, Page>,
+}
+
+impl VirtualRangeAllocator {
+    pub const fn new(space: Range<VirtualAddress>) -> Self {
+        VirtualRangeAllocator {
+            space,
+            allocated: BTreeMap::new(),
+        }
+    }
+
+    /// Allocates `count` pages, the first of which is aligned to a multiple of `align` pages.
+    pub fn allocate(&mut self, count: usize, align: usize) -> Result<Range<Page>, Error> {
+        let space_end = Page::containing(self.space.end);
+        let mut start = align_up(Page::containing(self.space.start), align);
+        for (&allocated_start, &allocated_end) in self.allocated.iter() {
+            if Step::forward(start, count) <= allocated_start {
+                break;
+            }
+            if allocated_end > start {
+                start = align_up(allocated_end, align);
+            }
+        }
+
+        let end = Step::forward(start, count);
+        if end > space_end {
+            return Err(Error::VirtualSpaceExhausted);
+        }
+        self.allocated.insert(start, end);
+        Ok(start..end)
+    }
+
+    /// Marks a specific range as allocated, for regions whose address was fixed by someone else.
+    pub fn reserve(&mut self, pages: Range<Page>) -> Result<(), Error> {
+        if pages.start.start_address() < self.space.start || pages.end.start_address() > self.space.end {
+            return Err(Error::Other("range is outside of the allocator's space"));
+        }
+        let previous = self.allocated.range(..pages.end).next_back();
+        if previous.is_some_and(|(_, &end)| end > pages.start) {
+            return Err(Error::AreaOverlaps);
+        }
+        self.allocated.insert(pages.start, pages.end);
+        Ok(())
+    }
+
+    /// Gets the allocated range that contains `page`, if there is one.
+    pub fn range_containing(&self, page: Page) -> Option<Range<Page>> {
+        self.allocated.range(..=page)
+            .next_back()
+            .filter(|(_, &end)| page < end)
+            .map(|(&start, &end)| start..end)
+    }
+
+    /// Frees the range starting at `start`, returning the whole range.
+    pub fn deallocate(&mut self, start: Page) -> Result<Range<Page>, Error> {
+        let end = self.allocated.remove(&start).ok_or(Error::NotReserved)?;
+        Ok(start..end)
+    }
+}
+
+fn align_up(page: Page, align: usize) -> Page {
+    Page::with_number(page.number().next_multiple_of(align))
+}
@@ -0,0 +1,133 @@
+//! Checks of the memory system that need real page tables, so they can't run as host tests.
+//!
+//! They run once at boot in debug builds, and panic on the first check that fails.
+
+use core::iter::Step;
+use crate::arch::prelude::*;
+use crate::memory::{Error, FlushPromise, Frame, KernelMemory, Mapping, Page, PageFlags, PageSize, PhysicalAddress, VirtualAddress};
+use crate::memory::ranges::MMIO_RANGES;
+
+/// Runs every check.
+pub fn run(kmm: &KernelMemory) {
+    unmap_inside_aligned_range(kmm);
+    count_partly_covered_huge_page(kmm);
+    clone_shares_user_pages(kmm);
+    log::info!("Memory self-tests passed");
+}
+
+/// Maps a 2 MiB-aligned range without [`PageFlags::HUGE`], and checks that a single page can be unmapped from the
+/// middle of it.
+fn unmap_inside_aligned_range(kmm: &KernelMemory) {
+    let count = PageSize::Size2MiB.pages();
+    let pages = MMIO_RANGES.lock().allocate(count, count).expect("failed to allocate a 2 MiB range");
+    let mut vmm = kmm.vmm();
+    vmm.try_map_range(pages.clone(), PageFlags::WRITABLE).expect("failed to map a 2 MiB range").flush();
+
+    let middle = Step::forward(pages.start, count / 2);
+    vmm.try_unmap(middle).expect("failed to unmap a page inside a 2 MiB range").flush();
+    assert!(vmm.virtual_to_physical(middle.start_address()).is_none(), "the unmapped page is still mapped");
+    assert_eq!(
+        vmm.mapped_pages(pages.start.start_address()..pages.end.start_address()), count - 1,
+        "unmapping one page unmapped its neighbours too");
+
+    vmm.try_unmap_range(pages.start..middle).expect("failed to unmap the rest of the range").flush();
+    vmm.try_unmap_range(Step::forward(middle, 1)..pages.end).expect("failed to unmap the rest of the range").flush();
+    drop(vmm);
+    MMIO_RANGES.lock().deallocate(pages.start).expect("failed to release the 2 MiB range");
+}
+
+/// Maps a 2 MiB page, and checks that counting the pages mapped in ranges that only partly cover it counts just the
+/// part they cover.
+fn count_partly_covered_huge_page(kmm: &KernelMemory) {
+    // The pages on either side of the huge page are ours too, so nothing else can be mapped there.
+    let count = PageSize::Size2MiB.pages();
+    let range = MMIO_RANGES.lock().allocate(3 * count, count).expect("failed to allocate a 6 MiB range");
+    let pages = Step::forward(range.start, count)..Step::forward(range.start, 2 * count);
+    let mut vmm = kmm.vmm();
+    unsafe {
+        // SAFETY: Nothing accesses the page, and borrowed frames are never freed.
+        vmm.try_map_range_to(pages.clone(), Frame::containing(PhysicalAddress::new(0)), PageFlags::HUGE)
+    }.expect("failed to map a 2 MiB page").flush();
+
+    let mut size = None;
+    vmm.walk_mappings(&mut |mapping| {
+        if mapping.page == pages.start {
+            size = Some(mapping.size);
+        }
+    });
+    assert_eq!(size, Some(PageSize::Size2MiB), "the range wasn't mapped with a 2 MiB page");
+
+    let (start, end) = (pages.start.start_address(), pages.end.start_address());
+    let page = VirtualMemoryManager::PAGE_SIZE;
+    assert_eq!(vmm.mapped_pages(start - page..start + page), 1, "a range over the start counted the wrong pages");
+    assert_eq!(vmm.mapped_pages(end - page..end + page), 1, "a range over the end counted the wrong pages");
+    assert_eq!(vmm.mapped_pages(start + page..end - page), count - 2, "a range inside counted the wrong pages");
+
+    vmm.try_unmap_range(pages).expect("failed to unmap a 2 MiB page").flush();
+    drop(vmm);
+    MMIO_RANGES.lock().deallocate(range.start).expect("failed to release the 6 MiB range");
+}
+
+/// Clones an address space with a few pages mapped, and checks that the clone maps exactly those pages, to the same
+/// frames, with the writable ones made copy-on-write.
+fn clone_shares_user_pages(kmm: &KernelMemory) {
+    // Pages under different level 4 and level 3 entries, so that a walk at the wrong level can't line up by accident.
+    let pages = [
+        (Page::containing(VirtualAddress::new(0x40_0000)), PageFlags::WRITABLE),
+        (Page::containing(VirtualAddress::new(0x40_1000)), PageFlags::empty()),
+        (Page::containing(VirtualAddress::new(0x80_4000_0000)), PageFlags::WRITABLE),
+    ];
+    let mut vmm = kmm.vmm();
+    let free_before = vmm.frame_stats().free;
+
+    let mut parent = AddressSpace::try_new(&mut vmm).expect("failed to create an address space");
+    for &(page, flags) in &pages {
+        parent.try_map(&mut vmm, page, flags).expect("failed to map a user page").flush();
+    }
+    let mut child = parent.try_clone(&mut vmm).expect("failed to clone an address space");
+
+    let (parent_count, parent_mappings) = user_mappings(&parent, &vmm);
+    let (child_count, child_mappings) = user_mappings(&child, &vmm);
+    assert_eq!(parent_count, pages.len(), "cloning changed the parent's user mappings");
+    assert_eq!(child_count, pages.len(), "the clone doesn't map exactly the parent's user pages");
+    for (index, &(page, _)) in pages.iter().enumerate() {
+        let parent_mapping = parent_mappings[index].as_ref().unwrap();
+        let child_mapping = child_mappings[index].as_ref().unwrap();
+        assert!(parent_mapping.page == page && child_mapping.page == page, "the clone maps the wrong pages");
+        assert!(parent_mapping.frame == child_mapping.frame, "the clone doesn't share the parent's frames");
+        assert!(
+            !parent_mapping.flags.contains(PageFlags::WRITABLE) && !child_mapping.flags.contains(PageFlags::WRITABLE),
+            "shared pages are still writable");
+    }
+
+    // Only the pages that were writable are copy-on-write, and copying one gives the clone a frame of its own.
+    child.try_resolve_copy_on_write(&mut vmm, pages[0].0).expect("a writable page wasn't made copy-on-write").flush();
+    assert!(
+        matches!(child.try_resolve_copy_on_write(&mut vmm, pages[1].0), Err(Error::AccessViolation)),
+        "a read-only page was made copy-on-write");
+    let (_, child_mappings) = user_mappings(&child, &vmm);
+    let copied = child_mappings[0].as_ref().unwrap();
+    assert!(copied.flags.contains(PageFlags::WRITABLE), "the copied page isn't writable");
+    assert!(copied.frame != parent_mappings[0].as_ref().unwrap().frame, "the copied page still shares its frame");
+
+    child.destroy(&mut vmm).expect("failed to destroy the clone");
+    parent.destroy(&mut vmm).expect("failed to destroy the address space");
+    assert_eq!(vmm.frame_stats().free, free_before, "destroying the address spaces leaked frames");
+}
+
+/// Gets the number of user pages an address space maps, and the first few of their mappings.
+///
+/// The VMM is locked, so the mappings go in an array: growing the heap for a vector would need the VMM too.
+fn user_mappings(space: &AddressSpace, vmm: &VirtualMemoryManager) -> (usize, [Option<Mapping>; 4]) {
+    let mut mappings: [Option<Mapping>; 4] = Default::default();
+    let mut count = 0;
+    space.walk_mappings(vmm, &mut |mapping| {
+        if VirtualMemoryManager::USER_SPACE.contains(&mapping.page.start_address()) {
+            if let Some(slot) = mappings.get_mut(count) {
+                *slot = Some(mapping);
+            }
+            count += 1;
+        }
+    });
+    (count, mappings)
+}
@@ -0,0 +1,55 @@
+use core::iter::Step;
+use core::ops::Range;
+use crate::arch::prelude::*;
+use crate::memory::{Error, FlushPromise, KernelMemory, Page, PageFlags, VirtualAddress};
+use crate::memory::ranges::STACK_RANGES;
+
+/// The number of unmapped guard pages below each stack.
+const GUARD_PAGES: usize = 1;
+
+/// A kernel stack in [`VirtualMemoryManagerProtocol::KERNEL_STACK_SPACE`].
+///
+/// Every stack is given its own range of the stack space, and the lowest page of the range is left unmapped, so
+/// overflowing the stack faults instead of silently corrupting whatever is next to it.
+pub struct KernelStack {
+    pages: Range<Page>,
+}
+
+impl KernelStack {
+    /// Allocates and maps a new stack of `size` bytes (rounded up to whole pages).
+    pub fn allocate(kmm: &KernelMemory, size: usize) -> Result<KernelStack, Error> {
+        let page_count = size.div_ceil(VirtualMemoryManager::PAGE_SIZE);
+        let range = STACK_RANGES.lock().allocate(page_count + GUARD_PAGES, 1)?;
+        let pages = Step::forward(range.start, GUARD_PAGES)..range.end;
+        let result = kmm.vmm().try_map_range(pages.clone(), PageFlags::WRITABLE);
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                STACK_RANGES.lock().deallocate(range.start)?;
+                return Err(e);
+            },
+        }
+
+        Ok(KernelStack { pages })
+    }
+
+    /// Gets the address just past the highest byte of the stack, which is where the stack pointer should start.
+    pub fn top(&self) -> VirtualAddress {
+        self.pages.end.start_address()
+    }
+
+    /// Gets the lowest address of the stack. The page below it is the guard page.
+    pub fn bottom(&self) -> VirtualAddress {
+        self.pages.start.start_address()
+    }
+
+    /// Unmaps the stack and makes its range of the stack space available for reuse.
+    ///
+    /// # Safety
+    /// The stack must not be in use.
+    pub unsafe fn free(self, kmm: &KernelMemory) -> Result<(), Error> {
+        kmm.vmm().try_unmap_range(self.pages.clone())?.flush();
+        STACK_RANGES.lock().deallocate(Step::backward(self.pages.start, GUARD_PAGES))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use core::ops::Range;
+use crate::arch::prelude::*;
+use crate::memory::VirtualAddress;
+
+/// A snapshot of how the kernel is using memory, from [`crate::memory::KernelMemory::stats`].
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+
+    /// The number of frames used for the kernel's page tables.
+    pub page_table_frames: usize,
+
+    /// The number of 4 KiB pages mapped in each part of kernel space.
+    pub spaces: [SpaceStats; KERNEL_SPACES.len()],
+}
+
+/// Physical frame usage.
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    /// Frames tracked by the frame allocator.
+    pub total: usize,
+
+    /// Frames that are currently free.
+    pub free: usize,
+
+    /// Frames the firmware reported as usable.
+    pub usable: usize,
+
+    /// Frames reserved by the bootloader, for the kernel image, boot info and its page tables.
+    pub bootloader: usize,
+
+    /// Frames reserved by the firmware, or otherwise unusable.
+    pub firmware: usize,
+
+    /// Bootloader and ACPI frames that were returned to the frame allocator at the end of early boot.
+    pub reclaimed: usize,
+}
+
+/// Kernel heap usage, in bytes.
+///
+/// Slabs are carved out of the heap, so memory in a slab counts as used even when none of its blocks are allocated.
+#[derive(Debug, Clone, Default)]
+pub struct HeapStats {
+    /// The current size of the heap.
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+
+    /// The most that has been in use at once.
+    pub peak: usize,
+
+    /// The largest size the heap may grow to.
+    pub limit: usize,
+}
+
+/// The number of pages mapped in one part of kernel space.
+#[derive(Debug, Clone)]
+pub struct SpaceStats {
+    pub name: &'static str,
+    pub mapped_pages: usize,
+}
+
+/// The parts of kernel space that statistics are gathered for.
+pub(super) const KERNEL_SPACES: [(&str, Range<VirtualAddress>); 6] = [
+    ("fixed", VirtualMemoryManager::KERNEL_FIXED_SPACE),
+    ("heap", VirtualMemoryManager::KERNEL_HEAP_SPACE),
+    ("stack", VirtualMemoryManager::KERNEL_STACK_SPACE),
+    ("mmio", VirtualMemoryManager::KERNEL_MMIO_SPACE),
+    ("boot", VirtualMemoryManager::KERNEL_BOOT_SPACE),
+    ("physical", VirtualMemoryManager::KERNEL_PHYSICAL_SPACE),
+];
@@ -0,0 +1,121 @@
+use core::marker::PhantomData;
+use crate::arch::prelude::*;
+use crate::memory::{Error, VirtualAddress};
+
+/// Data that can be copied from user memory as is, because every bit pattern is a valid value.
+///
+/// # Safety
+/// The type must not have any invalid bit patterns (like `bool` or references do), nor any padding.
+pub unsafe trait UserData: Copy {}
+
+unsafe impl UserData for u8 {}
+unsafe impl UserData for u16 {}
+unsafe impl UserData for u32 {}
+unsafe impl UserData for u64 {}
+unsafe impl UserData for usize {}
+unsafe impl UserData for i8 {}
+unsafe impl UserData for i16 {}
+unsafe impl UserData for i32 {}
+unsafe impl UserData for i64 {}
+unsafe impl UserData for isize {}
+unsafe impl<T: UserData, const N: usize> UserData for [T; N] {}
+
+/// A pointer to a `T` in user space, as handed to the kernel by user code.
+///
+/// The kernel must never dereference user pointers directly: they may point anywhere, and with SMAP enabled the access
+/// faults. Use [`UserPointer::read`] and [`UserPointer::write`] instead.
+#[derive(Debug)]
+pub struct UserPointer<T> {
+    address: VirtualAddress,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPointer<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPointer<T> {}
+
+impl<T: UserData> UserPointer<T> {
+    pub const fn new(address: VirtualAddress) -> Self {
+        UserPointer {
+            address,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    /// Gets a pointer to the `count`th `T` after this one.
+    pub fn offset(&self, count: usize) -> Self {
+        UserPointer::new(self.address + count * core::mem::size_of::<T>())
+    }
+
+    /// Copies the value out of user memory.
+    pub fn read(&self) -> Result<T, Error> {
+        let mut value = core::mem::MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            // SAFETY: The bytes are only read from once they have been filled in.
+            core::slice::from_raw_parts_mut(value.as_mut_ptr().cast::<u8>(), core::mem::size_of::<T>())
+        };
+        copy_from_user(bytes, self.address)?;
+        Ok(unsafe {
+            // SAFETY: Every byte was copied, and any bit pattern is a valid `T`.
+            value.assume_init()
+        })
+    }
+
+    /// Copies `value` into user memory.
+    pub fn write(&self, value: &T) -> Result<(), Error> {
+        let bytes = unsafe {
+            // SAFETY: `UserData` types have no padding, so every byte is initialized.
+            core::slice::from_raw_parts((value as *const T).cast::<u8>(), core::mem::size_of::<T>())
+        };
+        copy_to_user(self.address, bytes)
+    }
+}
+
+/// Copies `destination.len()` bytes from user memory at `source`.
+///
+/// Fails with [`Error::OutsideUserSpace`] if any of the bytes are outside of user space, and with
+/// [`Error::UserAccessFault`] if they aren't all mapped. Part of `destination` may have been written either way.
+pub fn copy_from_user(destination: &mut [u8], source: VirtualAddress) -> Result<(), Error> {
+    check_user_range(source, destination.len())?;
+    let left = unsafe {
+        // SAFETY: The source is in user space, and the destination is ours.
+        crate::arch::copy_user_bytes(destination.as_mut_ptr(), source.value() as *const u8, destination.len())
+    };
+    match left {
+        0 => Ok(()),
+        _ => Err(Error::UserAccessFault(source + (destination.len() - left))),
+    }
+}
+
+/// Copies `source` into user memory at `destination`.
+///
+/// Fails like [`copy_from_user`]. Part of the destination may have been written either way.
+pub fn copy_to_user(destination: VirtualAddress, source: &[u8]) -> Result<(), Error> {
+    check_user_range(destination, source.len())?;
+    let left = unsafe {
+        // SAFETY: The destination is in user space, and the source is ours.
+        crate::arch::copy_user_bytes(destination.value() as *mut u8, source.as_ptr(), source.len())
+    };
+    match left {
+        0 => Ok(()),
+        _ => Err(Error::UserAccessFault(destination + (source.len() - left))),
+    }
+}
+
+/// Checks that the `len` bytes at `start` lie entirely inside user space.
+fn check_user_range(start: VirtualAddress, len: usize) -> Result<(), Error> {
+    let end = start.value().checked_add(len).ok_or(Error::OutsideUserSpace)?;
+    let user_space = VirtualMemoryManager::USER_SPACE;
+    if !user_space.contains(&start) || end > user_space.end.value() {
+        return Err(Error::OutsideUserSpace);
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use crate::arch::prelude::*;
+use crate::memory::kmm::VMM;
+use crate::memory::{CpuLock, Error, FlushPromise, KernelMemory, Page, PageFlags, VirtualAddress};
+
+/// Every reserved area in the kernel's address space, keyed by its first page.
+static AREAS: CpuLock<BTreeMap<Page, VirtualMemoryArea>> = CpuLock::new(BTreeMap::new());
+
+/// A range of kernel virtual memory that has been reserved, but is only backed by frames once it is touched.
+#[derive(Debug, Clone)]
+pub struct VirtualMemoryArea {
+    pages: Range<Page>,
+    flags: PageFlags,
+}
+
+impl VirtualMemoryArea {
+    pub fn pages(&self) -> Range<Page> {
+        self.pages.clone()
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        self.flags
+    }
+
+    /// Checks whether the area's flags allow an access of the given kind.
+    fn allows(&self, access: FaultAccess) -> bool {
+        match access {
+            FaultAccess::Read => true,
+            FaultAccess::Write => self.flags.contains(PageFlags::WRITABLE),
+            FaultAccess::Execute => self.flags.contains(PageFlags::EXECUTABLE),
+        }
+    }
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+impl KernelMemory {
+    /// Reserves a range of kernel virtual memory without backing it.
+    ///
+    /// Each page is mapped to a zeroed frame with `flags` the first time it is touched, so large areas cost nothing
+    /// until they are used.
+    pub fn reserve(&self, pages: Range<Page>, flags: PageFlags) -> Result<(), Error> {
+        if flags.contains(PageFlags::WRITABLE | PageFlags::EXECUTABLE) {
+            return Err(Error::WritableAndExecutable);
+        }
+        if pages.is_empty()
+            || !VirtualMemoryManager::KERNEL_SPACE.contains(&pages.start.start_address())
+            || pages.end.start_address() > VirtualMemoryManager::KERNEL_SPACE.end {
+            return Err(Error::Other("areas must be non-empty and inside kernel space"));
+        }
+
+        let mut areas = AREAS.lock();
+        let previous = areas.range(..pages.end).next_back();
+        if previous.is_some_and(|(_, area)| area.pages.end > pages.start) {
+            return Err(Error::AreaOverlaps);
+        }
+        areas.insert(pages.start, VirtualMemoryArea { pages, flags });
+        Ok(())
+    }
+
+    /// Releases the area starting at `start`, unmapping every page of it that has been touched.
+    ///
+    /// # Safety
+    /// Nothing may use the memory in the area afterwards.
+    pub unsafe fn release(&self, start: Page) -> Result<(), Error> {
+        let pages = AREAS.lock().get(&start).ok_or(Error::NotReserved)?.pages();
+
+        // The area stays registered until every page is unmapped, so if unmapping fails, it can still be released.
+        let mut vmm = self.vmm();
+        for page in pages {
+            match vmm.try_unmap(page) {
+                Ok(flush) => flush.flush(),
+                Err(Error::PageNotMapped) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        drop(vmm);
+        AREAS.lock().remove(&start);
+        Ok(())
+    }
+
+    /// Gets the area that contains `address`, if it has been reserved.
+    pub fn area_containing(&self, address: VirtualAddress) -> Option<VirtualMemoryArea> {
+        find_area(&AREAS.lock(), address).cloned()
+    }
+}
+
+/// Resolves a fault on a page that isn't present by backing it, if it is inside a reserved area.
+///
+/// This is called from the page fault handler, so it doesn't wait for a lock the current CPU already holds: if the
+/// fault happened while this CPU had the VMM or the area registry locked, waiting for them would deadlock, and the
+/// fault is reported as an error instead. Locks held by other CPUs are waited for as usual.
+pub fn resolve_page_fault(address: VirtualAddress, access: FaultAccess) -> Result<(), Error> {
+    let flags = {
+        let areas = AREAS.lock_unless_held().ok_or(Error::Other("the area registry is locked by this CPU"))?;
+        let area = find_area(&areas, address).ok_or(Error::NotReserved)?;
+        if !area.allows(access) {
+            return Err(Error::AccessViolation);
+        }
+        area.flags
+    };
+
+    let vmm = VMM.get().ok_or(Error::Other("kernel memory has not been initialized"))?;
+    let mut vmm = vmm.lock_unless_held().ok_or(Error::Other("the VMM is locked by this CPU"))?;
+    match vmm.try_map_zeroed(Page::containing(address), flags) {
+        Ok(flush) => flush.flush(),
+        // Another processor faulted on the same page and backed it first.
+        Err(Error::PageAlreadyMapped(_)) => {},
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Resolves a write fault on a page that is present, by copying it if it is copy-on-write.
+///
+/// Like [`resolve_page_fault`], this won't wait for the VMM if the current CPU holds it.
+pub fn resolve_copy_on_write(address: VirtualAddress) -> Result<(), Error> {
+    let vmm = VMM.get().ok_or(Error::Other("kernel memory has not been initialized"))?;
+    let mut vmm = vmm.lock_unless_held().ok_or(Error::Other("the VMM is locked by this CPU"))?;
+    vmm.try_resolve_copy_on_write(Page::containing(address))?.flush();
+    Ok(())
+}
+
+fn find_area(areas: &BTreeMap<Page, VirtualMemoryArea>, address: VirtualAddress) -> Option<&VirtualMemoryArea> {
+    let page = Page::containing(address);
+    areas.range(..=page)
+        .next_back()
+        .map(|(_, area)| area)
+        .filter(|area| area.pages.contains(&page))
+}
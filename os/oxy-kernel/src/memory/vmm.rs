@@ -1,6 +1,5 @@
 use core::ops::Range;
-use crate::arch::prelude::VirtualMemoryManager;
-use crate::memory::{Error, Page, PageWritability, PhysicalAddress, VirtualAddress};
+use crate::memory::{Error, Frame, FrameStats, Mapping, Page, PageFlags, PhysicalAddress, VirtualAddress};
 
 #[must_use = "virtual memory changes must be flushed to take effect"]
 pub trait FlushPromise {
@@ -16,7 +15,7 @@ pub trait FlushPromise {
 /// Instead, the architecture-specific module is expected to export a struct with a known name that implements the protocol.
 /// Conditional compilation will be used to select the correct implementation based on the target architecture.
 pub trait VirtualMemoryManagerProtocol {
-    /// The type of promise returned by `try_map` that must be flushed to apply the changes.
+    /// The type of promise returned by mapping operations that must be flushed to apply the changes.
     type FlushPromise: FlushPromise;
 
     /// The size of a standard virtual memory page in bytes.
@@ -46,6 +45,9 @@ pub trait VirtualMemoryManagerProtocol {
     /// A range defining the kernel stack portion of the kernel-mode virtual address space.
     const KERNEL_STACK_SPACE: Range<VirtualAddress>;
 
+    /// A range defining the space used to map device registers and other memory-mapped I/O.
+    const KERNEL_MMIO_SPACE: Range<VirtualAddress>;
+
     /// A range defining the space used to map boot-time structures.
     /// This includes spaces for early-mode devices (VGA framebuffer, etc.) and the initial ramdrive.
     const KERNEL_BOOT_SPACE: Range<VirtualAddress>;
@@ -57,7 +59,92 @@ pub trait VirtualMemoryManagerProtocol {
     const KERNEL_PHYSICAL_SPACE: Range<VirtualAddress>;
 
     /// Attempts to map a page to an arbitrary frame.
-    fn try_map(&mut self, page: Page, writability: PageWritability) -> Result<Self::FlushPromise, Error>;
+    ///
+    /// Mappings that are both writable and executable are rejected with [`Error::WritableAndExecutable`].
+    fn try_map(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to map a page to an arbitrary frame that is zeroed before it becomes visible through the page.
+    fn try_map_zeroed(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to map a page to a specific frame.
+    ///
+    /// The frame is not taken from (and will not be returned to) the frame allocator, which makes this suitable for
+    /// mapping device memory.
+    ///
+    /// # Safety
+    /// The caller must ensure that mapping the frame does not create aliases to memory that is owned by someone else.
+    unsafe fn try_map_to(&mut self, page: Page, frame: Frame, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to map every page in a range to arbitrary frames.
+    ///
+    /// With [`PageFlags::HUGE`], larger page sizes are used wherever the range is suitably aligned and the frame
+    /// allocator can provide enough contiguous frames. If any page fails to map, the pages that were already mapped
+    /// are unmapped again before the error is returned.
+    fn try_map_range(&mut self, pages: Range<Page>, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to map every page in a range to the physically contiguous frames starting at `first_frame`.
+    ///
+    /// With [`PageFlags::HUGE`], larger page sizes are used wherever both the pages and the frames are suitably
+    /// aligned. As with
+    /// [`VirtualMemoryManagerProtocol::try_map_to`], the frames are not returned to the frame allocator on unmap.
+    ///
+    /// # Safety
+    /// Same as [`VirtualMemoryManagerProtocol::try_map_to`], for every frame in the range.
+    unsafe fn try_map_range_to(&mut self, pages: Range<Page>, first_frame: Frame, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to unmap a page, returning its backing frame to the frame allocator.
+    ///
+    /// Frames mapped with [`VirtualMemoryManagerProtocol::try_map_to`] are not returned to the frame allocator.
+    /// If `page` is the start of a huge page, the whole huge page is unmapped; unmapping a page from the middle of a
+    /// huge page fails with [`Error::InsideHugePage`].
+    ///
+    /// A frame is only returned to the frame allocator once its page has been invalidated on every CPU, so nothing
+    /// can reach it through a stale TLB entry after it has been handed out again. The returned promise covers the
+    /// rest, like pages that mapped borrowed frames.
+    fn try_unmap(&mut self, page: Page) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to unmap every page in a range, as if by [`VirtualMemoryManagerProtocol::try_unmap`].
+    ///
+    /// Every page in the range is checked before anything is unmapped, so on failure the range is left as it was.
+    fn try_unmap_range(&mut self, pages: Range<Page>) -> Result<Self::FlushPromise, Error>;
+
+    /// Attempts to replace the flags of a page that is already mapped.
+    ///
+    /// As with [`VirtualMemoryManagerProtocol::try_unmap`], this applies to the whole huge page if `page` starts one.
+    fn try_protect(&mut self, page: Page, flags: PageFlags) -> Result<Self::FlushPromise, Error>;
+
+    /// Resolves a write fault on a copy-on-write page in the active address space.
+    ///
+    /// If the page's frame is still shared with another address space, the page gets a private copy of it;
+    /// otherwise the page is simply made writable again. Fails with [`Error::AccessViolation`] if the page isn't
+    /// copy-on-write.
+    fn try_resolve_copy_on_write(&mut self, page: Page) -> Result<Self::FlushPromise, Error>;
+
+    /// Allocates `count` physically contiguous frames that all lie below `limit`, the first of which is aligned to a
+    /// multiple of `align` frames.
+    ///
+    /// The frames are only reachable through the physical memory map until they are mapped with
+    /// [`VirtualMemoryManagerProtocol::try_map_range_to`], and must be returned with
+    /// [`VirtualMemoryManagerProtocol::deallocate_contiguous`].
+    fn try_allocate_contiguous(&mut self, count: usize, align: usize, limit: PhysicalAddress) -> Result<Frame, Error>;
+
+    /// Returns frames allocated with [`VirtualMemoryManagerProtocol::try_allocate_contiguous`] to the frame allocator.
+    fn deallocate_contiguous(&mut self, frames: Range<Frame>);
+
+    /// Gets statistics about the usage of physical frames.
+    fn frame_stats(&self) -> FrameStats;
+
+    /// Counts the pages mapped by the kernel's page tables in `range`, with huge pages counted as the standard pages
+    /// they cover.
+    fn mapped_pages(&self, range: Range<VirtualAddress>) -> usize;
+
+    /// Counts the frames used for the kernel's page tables.
+    fn page_table_frames(&self) -> usize;
+
+    /// Visits every present mapping in the kernel's page tables, in address order.
+    ///
+    /// This doesn't allocate, so it can be used for debugging from anywhere the VMM can be locked.
+    fn walk_mappings(&self, visit: &mut dyn FnMut(Mapping));
 
     /// Gets the physical address that represents the given virtual address, if any.
     ///
@@ -66,8 +153,7 @@ pub trait VirtualMemoryManagerProtocol {
 
     /// Gets the virtual address that can be used to read from the given physical address.
     ///
-    /// Since we map the entire physical address space in to the virtual address space, this is trivial.
-    fn physical_to_virtual(&self, addr: PhysicalAddress) -> VirtualAddress {
-        VirtualMemoryManager::KERNEL_PHYSICAL_SPACE.start + addr.value()
-    }
+    /// Since we map the entire physical address space in to the virtual address space, this is trivial. Where it is
+    /// mapped is only decided at boot (see [`crate::memory::KernelLayout::physical_memory`]).
+    fn physical_to_virtual(&self, addr: PhysicalAddress) -> VirtualAddress;
 }
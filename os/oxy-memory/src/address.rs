@@ -0,0 +1,145 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Add, Sub};
+use crate::MemoryModelProtocol;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(transparent)]
+pub struct PhysicalAddress(usize);
+
+impl PhysicalAddress {
+    pub const fn new(value: usize) -> Self {
+        Self(value)
+    }
+
+    pub const fn value(&self) -> usize {
+        self.0
+    }
+
+    pub const fn is_aligned(&self, alignment: usize) -> bool {
+        self.value() % alignment == 0
+    }
+
+    pub const fn align_down(&self, alignment: usize) -> Self {
+        Self::new(self.value() & !(alignment - 1))
+    }
+
+    pub const fn align_up(&self, alignment: usize) -> Self {
+        Self::new((self.value() + alignment - 1) & !(alignment - 1))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct VirtualAddress<M: MemoryModelProtocol>(usize, PhantomData<M>);
+
+impl<M: MemoryModelProtocol> VirtualAddress<M> {
+    /// Creates a virtual address, making it canonical by copying the highest significant bit into the bits above it.
+    pub const fn new(value: usize) -> Self {
+        let shift = usize::BITS - M::VIRTUAL_ADDRESS_BITS;
+        Self((((value << shift) as isize) >> shift) as usize, PhantomData)
+    }
+
+    pub const fn value(&self) -> usize {
+        self.0
+    }
+
+    pub const fn is_aligned(&self, alignment: usize) -> bool {
+        self.value() % alignment == 0
+    }
+
+    pub const fn align_down(&self, alignment: usize) -> Self {
+        Self::new(self.value() & !(alignment - 1))
+    }
+
+    pub const fn align_up(&self, alignment: usize) -> Self {
+        Self::new((self.value() + alignment - 1) & !(alignment - 1))
+    }
+}
+
+impl Add<usize> for PhysicalAddress {
+    type Output = Self;
+
+    fn add(self, rhs: usize) -> Self::Output {
+        Self(self.0 + rhs)
+    }
+}
+
+impl Sub<usize> for PhysicalAddress {
+    type Output = Self;
+
+    fn sub(self, rhs: usize) -> Self::Output {
+        Self(self.0 - rhs)
+    }
+}
+
+impl fmt::Display for PhysicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}phys", self.0)
+    }
+}
+
+impl<M: MemoryModelProtocol> Add<usize> for VirtualAddress<M> {
+    type Output = Self;
+
+    fn add(self, rhs: usize) -> Self::Output {
+        Self(self.0 + rhs, PhantomData)
+    }
+}
+
+impl<M: MemoryModelProtocol> Sub<usize> for VirtualAddress<M> {
+    type Output = Self;
+
+    fn sub(self, rhs: usize) -> Self::Output {
+        Self(self.0 - rhs, PhantomData)
+    }
+}
+
+impl<M: MemoryModelProtocol> fmt::Display for VirtualAddress<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}virt", self.0)
+    }
+}
+
+impl<M: MemoryModelProtocol> fmt::Debug for VirtualAddress<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("VirtualAddress").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestModel;
+
+    type VirtualAddress = super::VirtualAddress<TestModel>;
+
+    #[test]
+    fn virtual_addresses_are_canonicalized() {
+        assert_eq!(VirtualAddress::new(0x7FFF_FFFF_F000).value(), 0x7FFF_FFFF_F000);
+        assert_eq!(VirtualAddress::new(0x8000_0000_0000).value(), 0xFFFF_8000_0000_0000);
+        assert_eq!(VirtualAddress::new(0xFFFF_8000_0000_0000).value(), 0xFFFF_8000_0000_0000);
+        assert_eq!(VirtualAddress::new(0x0001_0000_0000_1000).value(), 0x1000);
+    }
+
+    #[test]
+    fn alignment() {
+        let address = PhysicalAddress::new(0x1234);
+        assert!(!address.is_aligned(0x1000));
+        assert_eq!(address.align_down(0x1000), PhysicalAddress::new(0x1000));
+        assert_eq!(address.align_up(0x1000), PhysicalAddress::new(0x2000));
+        assert_eq!(PhysicalAddress::new(0x2000).align_up(0x1000), PhysicalAddress::new(0x2000));
+
+        let address = VirtualAddress::new(0x7FFF_FFFF_F123);
+        assert_eq!(address.align_down(0x1000), VirtualAddress::new(0x7FFF_FFFF_F000));
+
+        // Aligning up past the lower half lands in the (canonical) upper half.
+        assert_eq!(address.align_up(0x1000), VirtualAddress::new(0x8000_0000_0000));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(PhysicalAddress::new(0x1000).to_string(), "0x1000phys");
+        assert_eq!(VirtualAddress::new(0x8000_0000_0000).to_string(), "0xffff800000000000virt");
+    }
+}
@@ -0,0 +1,397 @@
+use core::marker::PhantomData;
+use core::ops::Range;
+use crate::{Frame, MemoryModelProtocol};
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// The number of bits used to count the extra references to each frame.
+const SHARE_BITS: usize = 16;
+const SHARES_PER_WORD: usize = BITS_PER_WORD / SHARE_BITS;
+const MAX_SHARES: u64 = (1 << SHARE_BITS) - 1;
+
+/// Allocates physical frames from a bitmap.
+///
+/// Every frame from frame number zero up to `frame_count` is tracked by one bit in the bitmap (set means free).
+/// A second, much smaller, summary bitmap has one bit per bitmap word, which is set when that word still has at least
+/// one free frame. Allocating only has to scan the summary (one bit per 64 frames, starting from a hint) and freeing
+/// is constant-time, so handing out frames no longer gets slower the more frames have been allocated.
+///
+/// Each allocated frame also has a reference count, so that a frame can be shared (for example, copy-on-write between
+/// address spaces) and is only freed when the last reference to it is dropped. The count is stored as the number of
+/// references beyond the first, in 16 bits per frame, so frames that are never shared don't need any bookkeeping.
+///
+/// The allocator does not own its storage; the architecture-specific boot code carves it out of usable memory and
+/// then marks the usable regions as free using [`PhysicalFrameAllocator::release_range`].
+pub struct PhysicalFrameAllocator<M: MemoryModelProtocol> {
+    bitmap: &'static mut [u64],
+    summary: &'static mut [u64],
+    shares: &'static mut [u64],
+    frame_count: usize,
+    free_count: usize,
+
+    /// The index of the first summary word that may contain a set bit.
+    summary_hint: usize,
+
+    model: PhantomData<M>,
+}
+
+impl<M: MemoryModelProtocol> PhysicalFrameAllocator<M> {
+    /// Gets the number of `u64` words of storage needed to track `frame_count` frames.
+    pub const fn storage_words(frame_count: usize) -> usize {
+        let bitmap_words = frame_count.div_ceil(BITS_PER_WORD);
+        let summary_words = bitmap_words.div_ceil(BITS_PER_WORD);
+        let share_words = frame_count.div_ceil(SHARES_PER_WORD);
+        bitmap_words + summary_words + share_words
+    }
+
+    /// Creates a new allocator tracking frames `0..frame_count`, with every frame initially marked as in use.
+    ///
+    /// # Safety
+    /// `storage` must be at least [`PhysicalFrameAllocator::storage_words`] long and must not be used for anything
+    /// else (including being handed out as a free frame) for as long as the allocator is alive.
+    pub unsafe fn new(storage: &'static mut [u64], frame_count: usize) -> Self {
+        let bitmap_words = frame_count.div_ceil(BITS_PER_WORD);
+        let summary_words = bitmap_words.div_ceil(BITS_PER_WORD);
+        let share_words = frame_count.div_ceil(SHARES_PER_WORD);
+        assert!(storage.len() >= bitmap_words + summary_words + share_words, "frame allocator storage is too small");
+
+        let (bitmap, rest) = storage.split_at_mut(bitmap_words);
+        let (summary, rest) = rest.split_at_mut(summary_words);
+        let shares = &mut rest[..share_words];
+        bitmap.fill(0);
+        summary.fill(0);
+        shares.fill(0);
+
+        PhysicalFrameAllocator {
+            bitmap,
+            summary,
+            shares,
+            frame_count,
+            free_count: 0,
+            summary_hint: summary_words,
+            model: PhantomData,
+        }
+    }
+
+    /// Moves the allocator's view of its storage by `offset` bytes, after the memory holding it has been mapped at a
+    /// new virtual address.
+    ///
+    /// # Safety
+    /// The storage must be mapped at its current address plus `offset`, and stay mapped there for as long as the
+    /// allocator is alive.
+    pub unsafe fn relocate_storage(&mut self, offset: isize) {
+        for words in [&mut self.bitmap, &mut self.summary, &mut self.shares] {
+            let len = words.len();
+            let start = core::mem::take(words).as_mut_ptr().byte_offset(offset);
+            *words = core::slice::from_raw_parts_mut(start, len);
+        }
+    }
+
+    /// Gets the total number of frames tracked by this allocator, whether free or not.
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Gets the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_count
+    }
+
+    /// Checks if the given frame is tracked by this allocator and currently free.
+    pub fn is_free(&self, frame: Frame<M>) -> bool {
+        let number = frame.number();
+        number < self.frame_count && self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    /// Allocates a single frame, returning `None` if there are no free frames left.
+    pub fn allocate(&mut self) -> Option<Frame<M>> {
+        while self.summary_hint < self.summary.len() {
+            let summary = self.summary[self.summary_hint];
+            if summary == 0 {
+                self.summary_hint += 1;
+                continue;
+            }
+
+            let word_index = self.summary_hint * BITS_PER_WORD + summary.trailing_zeros() as usize;
+            let bit = self.bitmap[word_index].trailing_zeros() as usize;
+            let number = word_index * BITS_PER_WORD + bit;
+            self.mark_used(number);
+            return Some(Frame::with_number(number));
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames, the first of which is aligned to a multiple of `align` frames.
+    ///
+    /// Unlike single-frame allocation this has to search the bitmap for a long enough run, so it is only meant for
+    /// large pages and other allocations that really need contiguous memory.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame<M>> {
+        self.allocate_contiguous_below(count, align, Frame::with_number(self.frame_count))
+    }
+
+    /// Allocates `count` physically contiguous frames that all lie below `end`, the first of which is aligned to a
+    /// multiple of `align` frames.
+    ///
+    /// This is for devices that can only reach part of physical memory, like 32-bit DMA engines.
+    pub fn allocate_contiguous_below(&mut self, count: usize, align: usize, end: Frame<M>) -> Option<Frame<M>> {
+        let end = end.number().min(self.frame_count);
+        let mut start = (self.summary_hint * BITS_PER_WORD * BITS_PER_WORD).next_multiple_of(align);
+        while start + count <= end {
+            match self.first_used(start..start + count) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for number in start..start + count {
+                        self.mark_used(number);
+                    }
+                    return Some(Frame::with_number(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Drops a reference to a frame, returning it to the allocator once no references are left.
+    ///
+    /// Frames outside of the range tracked by the allocator are ignored.
+    pub fn deallocate(&mut self, frame: Frame<M>) {
+        let number = frame.number();
+        if number >= self.frame_count {
+            return;
+        }
+        debug_assert!(!self.is_free(frame), "double free of {}", frame);
+        match self.shares(number) {
+            0 => self.mark_free(number),
+            shares => self.set_shares(number, shares - 1),
+        }
+    }
+
+    /// Adds a reference to an allocated frame, so that it takes one more [`PhysicalFrameAllocator::deallocate`] to
+    /// free it.
+    ///
+    /// Returns `false` if the frame isn't tracked by this allocator or already has the maximum number of references.
+    pub fn add_reference(&mut self, frame: Frame<M>) -> bool {
+        let number = frame.number();
+        if number >= self.frame_count || self.shares(number) == MAX_SHARES {
+            return false;
+        }
+        debug_assert!(!self.is_free(frame), "reference to free frame {}", frame);
+        self.set_shares(number, self.shares(number) + 1);
+        true
+    }
+
+    /// Gets the number of references to an allocated frame.
+    ///
+    /// Frames outside of the range tracked by the allocator always have a single reference.
+    pub fn references(&self, frame: Frame<M>) -> usize {
+        let number = frame.number();
+        if number >= self.frame_count {
+            return 1;
+        }
+        self.shares(number) as usize + 1
+    }
+
+    /// Marks every frame in `frames` as free, making it available for allocation.
+    ///
+    /// Frames outside of the range tracked by the allocator are ignored.
+    pub fn release_range(&mut self, frames: Range<Frame<M>>) {
+        for frame in frames {
+            if frame.number() < self.frame_count && !self.is_free(frame) {
+                self.mark_free(frame.number());
+            }
+        }
+    }
+
+    /// Marks every frame in `frames` as in use, so that it will not be allocated.
+    ///
+    /// Frames outside of the range tracked by the allocator are ignored.
+    pub fn reserve_range(&mut self, frames: Range<Frame<M>>) {
+        for frame in frames {
+            if self.is_free(frame) {
+                self.mark_used(frame.number());
+            }
+        }
+    }
+
+    /// Finds the first frame number in `numbers` that is in use.
+    fn first_used(&self, numbers: Range<usize>) -> Option<usize> {
+        let mut number = numbers.start;
+        while number < numbers.end {
+            // Invert the word so that set bits are used frames, then drop the frames below `number`.
+            let used = !self.bitmap[number / BITS_PER_WORD] >> (number % BITS_PER_WORD);
+            if used != 0 {
+                let found = number + used.trailing_zeros() as usize;
+                return if found < numbers.end { Some(found) } else { None };
+            }
+            number = (number / BITS_PER_WORD + 1) * BITS_PER_WORD;
+        }
+        None
+    }
+
+    fn shares(&self, number: usize) -> u64 {
+        (self.shares[number / SHARES_PER_WORD] >> (number % SHARES_PER_WORD * SHARE_BITS)) & MAX_SHARES
+    }
+
+    fn set_shares(&mut self, number: usize, shares: u64) {
+        let shift = number % SHARES_PER_WORD * SHARE_BITS;
+        let word = &mut self.shares[number / SHARES_PER_WORD];
+        *word = (*word & !(MAX_SHARES << shift)) | (shares << shift);
+    }
+
+    fn mark_used(&mut self, number: usize) {
+        let word_index = number / BITS_PER_WORD;
+        self.bitmap[word_index] &= !(1 << (number % BITS_PER_WORD));
+        if self.bitmap[word_index] == 0 {
+            self.summary[word_index / BITS_PER_WORD] &= !(1 << (word_index % BITS_PER_WORD));
+        }
+        self.free_count -= 1;
+    }
+
+    fn mark_free(&mut self, number: usize) {
+        let word_index = number / BITS_PER_WORD;
+        let summary_index = word_index / BITS_PER_WORD;
+        self.bitmap[word_index] |= 1 << (number % BITS_PER_WORD);
+        self.summary[summary_index] |= 1 << (word_index % BITS_PER_WORD);
+        self.summary_hint = self.summary_hint.min(summary_index);
+        self.free_count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestModel;
+
+    type Frame = crate::Frame<TestModel>;
+    type PhysicalFrameAllocator = super::PhysicalFrameAllocator<TestModel>;
+
+    /// Creates an allocator tracking `frame_count` frames, with `free` released.
+    fn allocator(frame_count: usize, free: Range<usize>) -> PhysicalFrameAllocator {
+        let storage = vec![0; PhysicalFrameAllocator::storage_words(frame_count)].leak();
+        let mut allocator = unsafe { PhysicalFrameAllocator::new(storage, frame_count) };
+        allocator.release_range(Frame::with_number(free.start)..Frame::with_number(free.end));
+        allocator
+    }
+
+    #[test]
+    fn frames_start_in_use() {
+        let allocator = allocator(100, 0..0);
+        assert_eq!(allocator.total_frames(), 100);
+        assert_eq!(allocator.free_frames(), 0);
+        assert!(!allocator.is_free(Frame::with_number(0)));
+    }
+
+    #[test]
+    fn allocates_lowest_free_frame_first() {
+        let mut allocator = allocator(200, 70..200);
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(70)));
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(71)));
+        assert_eq!(allocator.free_frames(), 128);
+
+        allocator.deallocate(Frame::with_number(70));
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(70)));
+    }
+
+    #[test]
+    fn runs_out_of_frames() {
+        let mut allocator = allocator(4, 0..4);
+        for number in 0..4 {
+            assert_eq!(allocator.allocate(), Some(Frame::with_number(number)));
+        }
+        assert_eq!(allocator.allocate(), None);
+        assert_eq!(allocator.free_frames(), 0);
+    }
+
+    #[test]
+    fn ignores_frames_it_does_not_track() {
+        let mut allocator = allocator(10, 0..20);
+        assert_eq!(allocator.free_frames(), 10);
+        allocator.deallocate(Frame::with_number(50));
+        assert_eq!(allocator.references(Frame::with_number(50)), 1);
+        assert!(!allocator.add_reference(Frame::with_number(50)));
+    }
+
+    #[test]
+    fn reserved_frames_are_not_allocated() {
+        let mut allocator = allocator(8, 0..8);
+        allocator.reserve_range(Frame::with_number(0)..Frame::with_number(6));
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(6)));
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(7)));
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn contiguous_allocations_are_aligned_and_skip_used_frames() {
+        let mut allocator = allocator(4096, 0..4096);
+        allocator.reserve_range(Frame::with_number(600)..Frame::with_number(601));
+
+        let first = allocator.allocate_contiguous(512, 512).unwrap();
+        assert_eq!(first, Frame::with_number(0));
+
+        // Frame 600 is in use, so the next aligned run starts at 1024.
+        let second = allocator.allocate_contiguous(512, 512).unwrap();
+        assert_eq!(second, Frame::with_number(1024));
+        assert!((1024..1536).all(|number| !allocator.is_free(Frame::with_number(number))));
+        assert!(allocator.is_free(Frame::with_number(601)));
+
+        assert_eq!(allocator.allocate_contiguous(4096, 1), None);
+    }
+
+    #[test]
+    fn contiguous_allocations_stay_below_the_limit() {
+        let mut allocator = allocator(4096, 0..4096);
+        allocator.reserve_range(Frame::with_number(0)..Frame::with_number(100));
+
+        let first = allocator.allocate_contiguous_below(16, 16, Frame::with_number(128)).unwrap();
+        assert_eq!(first, Frame::with_number(112));
+
+        // Only 12 frames are left below 128, and the limit must hold for the whole run.
+        assert_eq!(allocator.allocate_contiguous_below(16, 1, Frame::with_number(128)), None);
+        assert_eq!(allocator.allocate_contiguous_below(12, 1, Frame::with_number(128)), Some(Frame::with_number(100)));
+    }
+
+    #[test]
+    fn shared_frames_are_freed_with_their_last_reference() {
+        let mut allocator = allocator(64, 0..64);
+        let frame = allocator.allocate().unwrap();
+        assert_eq!(allocator.references(frame), 1);
+
+        assert!(allocator.add_reference(frame));
+        assert!(allocator.add_reference(frame));
+        assert_eq!(allocator.references(frame), 3);
+
+        allocator.deallocate(frame);
+        allocator.deallocate(frame);
+        assert!(!allocator.is_free(frame));
+        allocator.deallocate(frame);
+        assert!(allocator.is_free(frame));
+    }
+
+    #[test]
+    fn reference_counts_saturate() {
+        let mut allocator = allocator(64, 0..64);
+        let frame = allocator.allocate().unwrap();
+        let neighbour = allocator.allocate().unwrap();
+        for _ in 0..MAX_SHARES {
+            assert!(allocator.add_reference(frame));
+        }
+        assert!(!allocator.add_reference(frame));
+        assert_eq!(allocator.references(frame), MAX_SHARES as usize + 1);
+        assert_eq!(allocator.references(neighbour), 1);
+    }
+
+    #[test]
+    fn storage_can_be_relocated() {
+        let mut allocator = allocator(128, 0..128);
+        let frame = allocator.allocate().unwrap();
+
+        // Copy the storage somewhere else, as if it had been remapped, and point the allocator at the copy.
+        let words = PhysicalFrameAllocator::storage_words(128);
+        let old = allocator.bitmap.as_ptr();
+        let copy = unsafe { core::slice::from_raw_parts(old, words) }.to_vec().leak();
+        unsafe { allocator.relocate_storage((copy.as_ptr() as isize) - (old as isize)) };
+
+        assert!(!allocator.is_free(frame));
+        assert_eq!(allocator.allocate(), Some(Frame::with_number(1)));
+        assert_eq!(copy[0] & 0b11, 0);
+    }
+}
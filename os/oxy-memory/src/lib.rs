@@ -0,0 +1,49 @@
+//! Architecture-neutral memory types: addresses, pages, frames and the physical frame allocator.
+//!
+//! Nothing in here touches the hardware, so the whole crate builds (and is tested) on the host as well as in the
+//! kernel. Everything that depends on the processor, like the page size, comes from a [`MemoryModelProtocol`].
+
+#![cfg_attr(not(test), no_std)]
+
+#![feature(error_in_core)]
+#![feature(step_trait)]
+
+mod address;
+mod page;
+mod frame_allocator;
+#[cfg(feature = "x86_64")]
+mod x86_64;
+
+pub use address::{PhysicalAddress, VirtualAddress};
+pub use page::{Frame, Page};
+pub use frame_allocator::PhysicalFrameAllocator;
+
+use core::fmt::Debug;
+use thiserror::Error;
+
+/// Describes the page size and virtual address format of a processor.
+///
+/// This is only ever used as a type parameter, so implementations are normally empty enums.
+pub trait MemoryModelProtocol: Copy + Eq + Ord + Debug {
+    /// The size of a standard page (and frame), in bytes. Must be a power of two.
+    const PAGE_SIZE: usize;
+
+    /// The number of significant bits in a virtual address. In a canonical address, every bit above these is a copy
+    /// of the highest significant bit.
+    const VIRTUAL_ADDRESS_BITS: u32;
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+#[error("address is not properly aligned")]
+pub struct NotAlignedError;
+
+/// The memory model the tests use: 4 KiB pages and 48-bit virtual addresses, as on x86_64.
+#[cfg(test)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TestModel {}
+
+#[cfg(test)]
+impl MemoryModelProtocol for TestModel {
+    const PAGE_SIZE: usize = 4096;
+    const VIRTUAL_ADDRESS_BITS: u32 = 48;
+}
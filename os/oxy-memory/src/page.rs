@@ -0,0 +1,199 @@
+use core::fmt::{Debug, Display};
+use core::iter::Step;
+use core::ops::Range;
+use crate::{MemoryModelProtocol, NotAlignedError, PhysicalAddress, VirtualAddress};
+
+/// A standard-sized page of virtual memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Page<M: MemoryModelProtocol> {
+    start: VirtualAddress<M>
+}
+
+impl<M: MemoryModelProtocol> Display for Page<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Page({} @ {})", self.number(), self.start)
+    }
+}
+
+impl<M: MemoryModelProtocol> Page<M> {
+    pub fn new(start: VirtualAddress<M>) -> Result<Self, NotAlignedError> {
+        if !start.is_aligned(M::PAGE_SIZE) {
+            return Err(NotAlignedError);
+        }
+        Ok(Page { start })
+    }
+
+    pub fn with_number(number: usize) -> Self {
+        Page { start: VirtualAddress::new(number * M::PAGE_SIZE) }
+    }
+
+    pub fn containing(address: VirtualAddress<M>) -> Self {
+        let aligned = address.align_down(M::PAGE_SIZE);
+        debug_assert!(aligned.is_aligned(M::PAGE_SIZE));
+        Page { start: aligned }
+    }
+
+    /// Gets the pages that cover every address in `addresses`, including any partial pages at either end.
+    pub fn range_covering(addresses: Range<VirtualAddress<M>>) -> Range<Self> {
+        Page::containing(addresses.start)..Page::containing(addresses.end.align_up(M::PAGE_SIZE))
+    }
+
+    pub fn start_address(&self) -> VirtualAddress<M> {
+        self.start
+    }
+
+    pub fn number(&self) -> usize {
+        self.start.value() / M::PAGE_SIZE
+    }
+
+    /// Checks if this page could be the first page of a larger page of `bytes` bytes.
+    pub fn is_aligned(&self, bytes: usize) -> bool {
+        self.start.is_aligned(bytes)
+    }
+}
+
+impl<M: MemoryModelProtocol> Step for Page<M> {
+    fn steps_between(start: &Self, end: &Self) -> Option<usize> {
+        Step::steps_between(&start.number(), &end.number())
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        let new_number = Step::forward_checked(start.number(), count)?;
+        Some(Page::with_number(new_number))
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        let new_number = Step::backward_checked(start.number(), count)?;
+        Some(Page::with_number(new_number))
+    }
+}
+
+/// A standard-sized frame of physical memory.
+#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Frame<M: MemoryModelProtocol> {
+    start: PhysicalAddress,
+    model: core::marker::PhantomData<M>,
+}
+
+impl<M: MemoryModelProtocol> Display for Frame<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Frame({} @ {})", self.number(), self.start)
+    }
+}
+
+impl<M: MemoryModelProtocol> Debug for Frame<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Frame").field("start", &self.start).finish()
+    }
+}
+
+impl<M: MemoryModelProtocol> Frame<M> {
+    pub fn new(start: PhysicalAddress) -> Result<Self, NotAlignedError> {
+        if !start.is_aligned(M::PAGE_SIZE) {
+            return Err(NotAlignedError);
+        }
+        Ok(Frame::at(start))
+    }
+
+    pub fn with_number(number: usize) -> Self {
+        Frame::at(PhysicalAddress::new(number * M::PAGE_SIZE))
+    }
+
+    pub fn containing(address: PhysicalAddress) -> Self {
+        let aligned = address.align_down(M::PAGE_SIZE);
+        debug_assert!(aligned.is_aligned(M::PAGE_SIZE));
+        Frame::at(aligned)
+    }
+
+    /// Gets the frames that cover every address in `addresses`, including any partial frames at either end.
+    pub fn range_covering(addresses: Range<PhysicalAddress>) -> Range<Self> {
+        Frame::containing(addresses.start)..Frame::containing(addresses.end.align_up(M::PAGE_SIZE))
+    }
+
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.start
+    }
+
+    pub fn number(&self) -> usize {
+        self.start.value() / M::PAGE_SIZE
+    }
+
+    /// Checks if this frame could be the first frame of a larger page of `bytes` bytes.
+    pub fn is_aligned(&self, bytes: usize) -> bool {
+        self.start.is_aligned(bytes)
+    }
+
+    const fn at(start: PhysicalAddress) -> Self {
+        Frame { start, model: core::marker::PhantomData }
+    }
+}
+
+impl<M: MemoryModelProtocol> Step for Frame<M> {
+    fn steps_between(start: &Self, end: &Self) -> Option<usize> {
+        Step::steps_between(&start.number(), &end.number())
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        let new_number = Step::forward_checked(start.number(), count)?;
+        Some(Frame::with_number(new_number))
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        let new_number = Step::backward_checked(start.number(), count)?;
+        Some(Frame::with_number(new_number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestModel;
+
+    type Page = super::Page<TestModel>;
+    type Frame = super::Frame<TestModel>;
+    type VirtualAddress = crate::VirtualAddress<TestModel>;
+
+    #[test]
+    fn new_requires_alignment() {
+        assert_eq!(Page::new(VirtualAddress::new(0x1001)), Err(NotAlignedError));
+        assert_eq!(Page::new(VirtualAddress::new(0x1000)).unwrap().number(), 1);
+        assert_eq!(Frame::new(PhysicalAddress::new(0x2fff)), Err(NotAlignedError));
+        assert_eq!(Frame::new(PhysicalAddress::new(0x3000)).unwrap().number(), 3);
+    }
+
+    #[test]
+    fn containing_rounds_down() {
+        assert_eq!(Page::containing(VirtualAddress::new(0x1fff)), Page::with_number(1));
+        assert_eq!(Frame::containing(PhysicalAddress::new(0x2000)), Frame::with_number(2));
+    }
+
+    #[test]
+    fn upper_half_page_numbers_round_trip() {
+        let page = Page::containing(VirtualAddress::new(0xFFFF_8000_0000_1000));
+        assert_eq!(Page::with_number(page.number()), page);
+        assert_eq!(Step::forward(page, 1).start_address(), VirtualAddress::new(0xFFFF_8000_0000_2000));
+    }
+
+    #[test]
+    fn range_covering_includes_partial_pages() {
+        let pages = Page::range_covering(VirtualAddress::new(0x1800)..VirtualAddress::new(0x3001));
+        assert_eq!(pages, Page::with_number(1)..Page::with_number(4));
+
+        let frames = Frame::range_covering(PhysicalAddress::new(0x1000)..PhysicalAddress::new(0x3000));
+        assert_eq!(frames, Frame::with_number(1)..Frame::with_number(3));
+    }
+
+    #[test]
+    fn ranges_can_be_iterated() {
+        let pages = Page::with_number(5)..Page::with_number(8);
+        assert_eq!(Step::steps_between(&pages.start, &pages.end), Some(3));
+        assert_eq!(pages.map(|p| p.number()).collect::<Vec<_>>(), [5, 6, 7]);
+    }
+
+    #[test]
+    fn huge_page_alignment() {
+        assert!(Frame::with_number(512).is_aligned(2 * 1024 * 1024));
+        assert!(!Frame::with_number(513).is_aligned(2 * 1024 * 1024));
+        assert!(Page::with_number(0).is_aligned(1024 * 1024 * 1024));
+    }
+}
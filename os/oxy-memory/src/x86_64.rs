@@ -0,0 +1,64 @@
+//! Conversions to and from the `x86_64` crate's types.
+//!
+//! These live here rather than in the kernel because the kernel can't implement `From` between two types that are
+//! both defined in other crates.
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{self as paging, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
+use crate::{Frame, MemoryModelProtocol, Page, PhysicalAddress, PhysicalFrameAllocator, VirtualAddress};
+
+impl From<PhysAddr> for PhysicalAddress {
+    fn from(address: PhysAddr) -> Self {
+        PhysicalAddress::new(address.as_u64() as usize)
+    }
+}
+
+impl From<PhysicalAddress> for PhysAddr {
+    fn from(address: PhysicalAddress) -> Self {
+        PhysAddr::new_truncate(address.value() as u64)
+    }
+}
+
+impl<M: MemoryModelProtocol> From<VirtAddr> for VirtualAddress<M> {
+    fn from(address: VirtAddr) -> Self {
+        VirtualAddress::new(address.as_u64() as usize)
+    }
+}
+
+impl<M: MemoryModelProtocol> From<VirtualAddress<M>> for VirtAddr {
+    fn from(address: VirtualAddress<M>) -> Self {
+        VirtAddr::new_truncate(address.value() as u64)
+    }
+}
+
+impl<M: MemoryModelProtocol> From<Page<M>> for paging::Page {
+    fn from(page: Page<M>) -> Self {
+        paging::Page::from_start_address(page.start_address().into()).unwrap()
+    }
+}
+
+impl<M: MemoryModelProtocol, S: PageSize> From<PhysFrame<S>> for Frame<M> {
+    fn from(frame: PhysFrame<S>) -> Self {
+        Frame::containing(frame.start_address().into())
+    }
+}
+
+impl<M: MemoryModelProtocol, S: PageSize> From<Frame<M>> for PhysFrame<S> {
+    fn from(frame: Frame<M>) -> Self {
+        PhysFrame::containing_address(frame.start_address().into())
+    }
+}
+
+/// Lets the `x86_64` crate's page table code allocate page tables, which are always 4 KiB.
+unsafe impl<M: MemoryModelProtocol> FrameAllocator<Size4KiB> for PhysicalFrameAllocator<M> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        debug_assert_eq!(M::PAGE_SIZE as u64, Size4KiB::SIZE, "frames are not 4 KiB");
+        self.allocate().map(|f| f.into())
+    }
+}
+
+impl<M: MemoryModelProtocol> FrameDeallocator<Size4KiB> for PhysicalFrameAllocator<M> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame.into())
+    }
+}